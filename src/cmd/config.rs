@@ -1,6 +1,8 @@
 use crate::config::{
     self,
-    models::{EmailConfig, Settings, WacraftConfig},
+    models::{
+        ActionRetryPolicies, ApiRetryPolicies, EmailConfig, EmailTransport, Settings, WacraftConfig,
+    },
 };
 use anyhow::{Context, Result};
 use clap::Subcommand;
@@ -55,14 +57,24 @@ fn init_config_files(force: bool) -> Result<()> {
             access_token: None,
             refresh_token: None,
             token_expires_at: None,
+            retry: ApiRetryPolicies::default(),
+            max_requests_per_second: 10,
         },
         email: EmailConfig {
+            transport: EmailTransport::Smtp,
             smtp_server: "smtp.example.com".to_string(),
             smtp_port: 587,
-            smtp_user: "user@example.com".to_string(),
-            smtp_password: "your_smtp_password".to_string(),
+            smtp_user: Some("user@example.com".to_string()),
+            smtp_password: Some("your_smtp_password".to_string()),
+            smtp_tls: None,
+            smtp_timeout_secs: 30,
+            smtp_max_connections: 10,
+            max_messages_per_minute: None,
+            sendmail_command: None,
+            output_dir: None,
             from_address: "reminders@wacraft.com".to_string(),
         },
+        retry: ActionRetryPolicies::default(),
     };
 
     // Create empty reminders list
@@ -108,6 +120,12 @@ fn view_config_files() -> Result<()> {
         Ok(reminders) => {
             let reminders_json = serde_json::to_string_pretty(&reminders)?;
             println!("{}", reminders_json);
+            for warning in reminders
+                .iter()
+                .flat_map(config::models::ReminderRule::warnings)
+            {
+                println!("⚠️  {}", warning);
+            }
         }
         Err(_) => {
             println!(
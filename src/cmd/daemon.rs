@@ -1,5 +1,7 @@
-use crate::daemon;
+use crate::config::models::CronSchedule;
+use crate::daemon::{self, DaemonOptions};
 use anyhow::Result;
+use chrono_tz::Tz;
 use clap::Subcommand;
 
 /// Actions for running the background daemon.
@@ -8,13 +10,26 @@ pub enum DaemonAction {
     /// Starts the daemon to periodically check for inactive contacts and send reminders.
     Run {
         /// The interval, in seconds, between each check.
-        #[arg(long, default_value = "3600")]
+        #[arg(long, default_value = "3600", value_parser = clap::value_parser!(u64).range(1..))]
         interval: u64,
 
+        /// A cron expression for when to check, used instead of the interval
+        /// (e.g., "0 9,14 * * Mon-Fri" for weekdays at 09:00 and 14:00).
+        #[arg(long, conflicts_with = "interval")]
+        schedule: Option<CronSchedule>,
+
+        /// The IANA timezone cron schedules are evaluated in.
+        #[arg(long, default_value = "UTC")]
+        timezone: Tz,
+
         /// The number of conversations to fetch from the API in each batch.
         #[arg(long, default_value = "100")]
         batch_size: u32,
 
+        /// How many contacts to process at the same time.
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
+
         /// Run the daemon in a detached (background) process.
         #[arg(long)]
         detached: bool,
@@ -38,22 +53,33 @@ pub async fn handle_daemon_command(action: DaemonAction) -> Result<()> {
     match action {
         DaemonAction::Run {
             interval,
+            schedule,
+            timezone,
             batch_size,
+            concurrency,
             detached,
             internal_run_detached,
             mock,
         } => {
+            let options = DaemonOptions {
+                interval_secs: interval,
+                schedule,
+                timezone,
+                batch_size,
+                concurrency,
+                mock,
+            };
             if internal_run_detached {
                 // This is the child process, run the actual daemon logic.
-                daemon::run_daemon_process(interval, batch_size, mock, true).await?;
+                daemon::run_daemon_process(options, true).await?;
             } else if detached {
                 // This is the parent process, detach and exit.
                 // Pass all relevant arguments to the detach function.
-                daemon::detach_process(interval, batch_size, mock)?;
+                daemon::detach_process(&options)?;
             } else {
                 // Run in the foreground.
                 println!("Running daemon in foreground. Press Ctrl+C to stop.");
-                daemon::run_daemon_process(interval, batch_size, mock, false).await?;
+                daemon::run_daemon_process(options, false).await?;
             }
         }
         DaemonAction::Stop => {
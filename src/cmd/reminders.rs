@@ -1,179 +1,526 @@
 use crate::config;
-use crate::core::wacraft::models::{Conversation, MessagePayloadBase, Order};
-use crate::core::wacraft::{
-    client::WacraftClient,
-    models::{MessagePayload, SendWhatsAppMessage},
-};
-use crate::core::{email, http_request};
+use crate::core::engine::{PlanEntry, ReminderEngine, RuleSelection};
+use crate::core::retry;
+use crate::core::wacraft::pager::ConversationPager;
+use crate::store::dead_letters::{self, DeadLetter};
+use crate::store::ledger::{self, DeliveryStatus};
 use anyhow::{Context, Result, anyhow};
-use chrono::{Duration, Utc};
-use clap::Subcommand;
-use log::info;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use clap::{Subcommand, ValueEnum};
+use log::error;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 /// Actions for managing and sending reminders.
 #[derive(Subcommand, Debug)]
 pub enum RemindersAction {
-    /// Sends the appropriate reminder to a specific contact based on their inactivity.
+    /// Sends the appropriate reminder to specific contacts based on their inactivity.
     Send {
-        /// The unique ID of the messaging product contact to send the reminder to.
+        /// The unique ID of a messaging product contact to send the reminder to.
+        /// Can be given several times.
+        #[arg(
+            long = "contact-id",
+            value_name = "CONTACT_ID",
+            required_unless_present = "file"
+        )]
+        contact_ids: Vec<String>,
+
+        /// A file with one contact ID per line, or `-` to read them from stdin.
+        /// Blank lines and lines starting with `#` are ignored.
         #[arg(long)]
-        contact_id: String,
+        file: Option<PathBuf>,
+
+        /// How many contacts to process at the same time.
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+        concurrency: u32,
 
         /// (Internal) Skips actual message sending, useful for testing.
         #[arg(long, hide = true)]
         mock: bool,
     },
+    /// Shows, per contact, which rule would run and when the next one becomes due,
+    /// without sending or recording anything.
+    Plan {
+        /// How to print the plan.
+        #[arg(long, value_enum, default_value_t = PlanFormat::Table)]
+        format: PlanFormat,
+
+        /// The number of conversations to fetch from the API in each batch.
+        #[arg(long, default_value = "100")]
+        batch_size: u32,
+
+        /// The IANA timezone rule schedules are evaluated in, as given to the daemon.
+        #[arg(long, default_value = "UTC")]
+        timezone: Tz,
+    },
+    /// Lists reminders that are due but waiting for their sending window to open.
+    Deferred,
+    /// Inspects reminder deliveries that failed after all retries.
+    Failed {
+        #[command(subcommand)]
+        action: FailedAction,
+    },
+    /// Replays failed deliveries with their stored payload, without re-evaluating rules.
+    Retry {
+        /// The ID of a failed delivery to replay. Can be given several times.
+        #[arg(long = "id", required_unless_present = "all", conflicts_with = "all")]
+        ids: Vec<String>,
+
+        /// Replays every failed delivery.
+        #[arg(long)]
+        all: bool,
+
+        /// Also replays deliveries from before the contact was last active. Those are
+        /// skipped by default, since the reminder no longer applies.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Actions for failed deliveries.
+#[derive(Subcommand, Debug)]
+pub enum FailedAction {
+    /// Lists failed deliveries, most recent first.
+    List,
+}
+
+/// Output formats for `reminders plan`.
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum PlanFormat {
+    Table,
+    Json,
+    Csv,
 }
 
 /// Handles the `reminders` subcommand.
 pub async fn handle_reminders_command(action: RemindersAction) -> Result<()> {
     match action {
-        RemindersAction::Send { contact_id, mock } => {
-            // We need to load settings here to pass the email config down.
-            let settings = config::load_settings().context("Failed to load settings.json")?;
-            send_reminder_to_contact(&contact_id, &settings, None, mock).await?;
+        RemindersAction::Send {
+            mut contact_ids,
+            file,
+            concurrency,
+            mock,
+        } => {
+            if let Some(path) = &file {
+                contact_ids.extend(read_contact_ids(path)?);
+            }
+            send_reminders(contact_ids, concurrency, mock).await?;
+        }
+        RemindersAction::Plan {
+            format,
+            batch_size,
+            timezone,
+        } => {
+            plan(format, batch_size, timezone).await?;
+        }
+        RemindersAction::Deferred => {
+            list_deferred()?;
+        }
+        RemindersAction::Failed {
+            action: FailedAction::List,
+        } => {
+            list_failed()?;
+        }
+        RemindersAction::Retry { ids, all, force } => {
+            retry_failed(&ids, all, force).await?;
         }
     }
     Ok(())
 }
 
-/// The core logic for sending a reminder to a single contact.
-pub async fn send_reminder_to_contact(
-    contact_id: &str,
-    settings: &config::models::Settings,
-    conversation: Option<&Conversation>,
-    mock: bool,
-) -> Result<()> {
-    info!("Preparing to send reminder to contact: {}", contact_id);
-
-    // 1. Load reminder rules
-    let reminders = config::load_reminders().context("Failed to load reminders.json.")?;
-    if reminders.is_empty() {
-        info!(
-            "No reminder rules found. Nothing to do for contact {}.",
-            contact_id
-        );
-        return Ok(());
+/// Reads contact IDs from a file, one per line, or from stdin when `path` is `-`.
+fn read_contact_ids(path: &Path) -> Result<Vec<String>> {
+    let content = if path == Path::new("-") {
+        let mut content = String::new();
+        io::stdin()
+            .read_to_string(&mut content)
+            .context("Failed to read contact IDs from stdin")?;
+        content
+    } else {
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read contact IDs from {:?}", path))?
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Evaluates the rules for each contact and sends the due reminders, up to
+/// `concurrency` contacts at a time, then prints a summary. Fails when any
+/// contact failed.
+async fn send_reminders(contact_ids: Vec<String>, concurrency: u32, mock: bool) -> Result<()> {
+    // A contact listed twice would race with itself on the ledger.
+    let mut seen = HashSet::new();
+    let contact_ids: Vec<String> = contact_ids
+        .into_iter()
+        .filter(|id| seen.insert(id.clone()))
+        .collect();
+    if contact_ids.is_empty() {
+        anyhow::bail!("No contact IDs given.");
     }
 
-    // 2. Initialize Wacraft Client
-    let client = WacraftClient::new(settings.wacraft.clone());
+    let engine = Arc::new(ReminderEngine::load(mock)?);
+    // Contacts are unique, and each only records its own steps, so one snapshot serves all.
+    let delivery_ledger = Arc::new(ledger::load().context("Failed to load the delivery ledger.")?);
+    let permits = Arc::new(Semaphore::new(concurrency.max(1) as usize));
+    let mut tasks = JoinSet::new();
+    for (index, contact_id) in contact_ids.iter().cloned().enumerate() {
+        let permit = permits.clone().acquire_owned().await?;
+        let (engine, delivery_ledger) = (engine.clone(), delivery_ledger.clone());
+        tasks.spawn(async move {
+            let _permit = permit;
+            let result = engine
+                .process_contact(&contact_id, None, &delivery_ledger, &RuleSelection::All)
+                .await;
+            (index, result)
+        });
+    }
 
-    // 3. Fetch the latest conversation for the contact
-    // Fetch the latest conversation for user if is not provided.
-    let latest_conversation = if let Some(conv) = conversation {
-        conv
-    } else {
-        let conversations = client
-            .get_conversation_messages(contact_id, 1, 0, None, Some(Order::Desc), None)
-            .await?;
-
-        &conversations
-            .into_iter()
-            .next()
-            .ok_or_else(|| anyhow!("No conversation found"))?
+    let mut results: Vec<Option<Result<()>>> = contact_ids.iter().map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, result)) => results[index] = Some(result),
+            Err(e) => error!("A contact processing task failed: {:?}", e),
+        }
+    }
+
+    // A single contact's outcome is already printed above.
+    if contact_ids.len() == 1
+        && let Some(Some(result)) = results.pop()
+    {
+        return result;
+    }
+
+    println!();
+    println!("{:<38} RESULT", "CONTACT ID");
+    let mut failed = 0;
+    for (contact_id, result) in contact_ids.iter().zip(&results) {
+        match result {
+            Some(Ok(())) => println!("{:<38} ✅ ok", contact_id),
+            Some(Err(e)) => {
+                failed += 1;
+                println!("{:<38} ❌ {:#}", contact_id, e);
+            }
+            None => {
+                failed += 1;
+                println!("{:<38} ❌ processing task failed", contact_id);
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} contacts failed.", failed, contact_ids.len());
+    }
+    println!("All {} contacts processed.", contact_ids.len());
+    Ok(())
+}
+
+/// Walks every conversation and prints what the daemon would do next for each
+/// contact. The API is only read from, and nothing is recorded.
+async fn plan(format: PlanFormat, batch_size: u32, timezone: Tz) -> Result<()> {
+    // Plans never deliver; mock mode makes sure nothing slips through.
+    let engine = ReminderEngine::load(true)?;
+    let delivery_ledger = ledger::load().context("Failed to load the delivery ledger.")?;
+    let mut pager = ConversationPager::new(engine.client(), batch_size, Utc::now());
+    let mut entries = Vec::new();
+
+    while let Some(conversations) = pager.next_page().await? {
+        for conversation in &conversations {
+            let Some(contact_id) = conversation.contact_id() else {
+                continue;
+            };
+            match engine
+                .plan_contact(contact_id, Some(conversation), &delivery_ledger, timezone)
+                .await
+            {
+                Ok(contact_entries) => entries.extend(contact_entries),
+                Err(e) => entries.push(PlanEntry::failed(contact_id, &e)),
+            }
+        }
+    }
+
+    match format {
+        PlanFormat::Table => print_plan_table(&entries),
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&entries)?),
+        PlanFormat::Csv => print_plan_csv(&entries),
+    }
+    Ok(())
+}
+
+/// Prints the plan as a table, with each rule's actions below it.
+fn print_plan_table(entries: &[PlanEntry]) {
+    if entries.is_empty() {
+        println!("No contacts to plan for.");
+        return;
+    }
+    let time = |at: Option<DateTime<Utc>>| {
+        at.map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|| "-".to_string())
     };
+    let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
 
-    const NIL_UUID: &str = "00000000-0000-0000-0000-000000000000";
-
-    // First, find a valid contact reference from the conversation, if any.
-    let contact_ref = latest_conversation
-        .to_contact
-        .as_ref()
-        .filter(|c| c.id != NIL_UUID)
-        .or_else(|| {
-            latest_conversation
-                .from_contact
-                .as_ref()
-                .filter(|c| c.id != NIL_UUID)
-        });
+    println!(
+        "{:<38} {:<16} {:<9} {:<12} {:<24} {:<17} {:<24} NEXT DUE (UTC)",
+        "CONTACT ID", "SEQUENCE", "INACTIVE", "STATUS", "RULE", "DUE (UTC)", "NEXT RULE"
+    );
+    for entry in entries {
+        let inactive = entry
+            .inactive_hours
+            .map(|hours| format!("{}h", hours))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<38} {:<16} {:<9} {:<12} {:<24} {:<17} {:<24} {}",
+            entry.contact_id,
+            text(&entry.sequence),
+            inactive,
+            entry.status,
+            text(&entry.rule),
+            time(entry.due_at),
+            text(&entry.next_rule),
+            time(entry.next_due_at)
+        );
+        for action in &entry.actions {
+            match (&action.target, &action.error) {
+                (_, Some(error)) => println!("    {}: error: {}", action.kind, error),
+                (Some(target), None) => println!("    {} → {}", action.kind, target),
+                (None, None) => println!("    {}", action.kind),
+            }
+        }
+        if let Some(error) = &entry.error {
+            println!("    error: {}", error);
+        }
+    }
+}
+
+/// Prints the plan as CSV, one row per contact and sequence.
+fn print_plan_csv(entries: &[PlanEntry]) {
+    println!(
+        "contact_id,contact_name,sequence,inactive_since,inactive_hours,status,rule,due_at,actions,next_rule,next_due_at,error"
+    );
+    let time = |at: Option<DateTime<Utc>>| at.map(|at| at.to_rfc3339()).unwrap_or_default();
+    for entry in entries {
+        let actions: Vec<String> = entry
+            .actions
+            .iter()
+            .map(|action| match (&action.target, &action.error) {
+                (_, Some(error)) => format!("{}: error: {}", action.kind, error),
+                (Some(target), None) => format!("{}: {}", action.kind, target),
+                (None, None) => action.kind.to_string(),
+            })
+            .collect();
+        let fields = [
+            entry.contact_id.clone(),
+            entry.contact_name.clone().unwrap_or_default(),
+            entry.sequence.clone().unwrap_or_default(),
+            time(entry.inactive_since),
+            entry
+                .inactive_hours
+                .map(|hours| hours.to_string())
+                .unwrap_or_default(),
+            entry.status.to_string(),
+            entry.rule.clone().unwrap_or_default(),
+            time(entry.due_at),
+            actions.join("; "),
+            entry.next_rule.clone().unwrap_or_default(),
+            time(entry.next_due_at),
+            entry.error.clone().unwrap_or_default(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        println!("{}", row.join(","));
+    }
+}
 
-    // Now, get an owned `Contact`.
-    // If we found a reference, clone it. Otherwise, fetch it from the client.
-    // This assumes the async function returns a `Result<Contact, Error>` and `Contact` implements `Clone`.
-    let contact = if let Some(ctt) = contact_ref {
-        ctt
+/// Quotes a CSV field when it contains a separator, a quote or a line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
     } else {
-        &client
-            .get_messaging_product_contact_by_id(contact_id)
-            .await?
-            .ok_or_else(|| anyhow!("No messaging product contact found"))?
-    };
+        value.to_string()
+    }
+}
 
-    // 4. Determine which reminder rule applies
-    let last_message_time = latest_conversation.updated_at;
-    let inactive_duration = Utc::now().signed_duration_since(last_message_time);
+/// Prints the deferred steps recorded in the ledger, soonest first.
+fn list_deferred() -> Result<()> {
+    let delivery_ledger = ledger::load().context("Failed to load the delivery ledger.")?;
+    let mut deferred: Vec<_> = delivery_ledger.deferred().collect();
+    if deferred.is_empty() {
+        println!("No deferred reminders.");
+        return Ok(());
+    }
+    deferred.sort_by_key(|(_, record)| record.deferred_until);
 
-    let mut applicable_rules = reminders;
-    applicable_rules.sort_by(|a, b| b.inactive_for_hours.cmp(&a.inactive_for_hours));
+    println!("{:<38} {:<30} DEFERRED UNTIL (UTC)", "CONTACT ID", "RULE");
+    for (contact_id, record) in deferred {
+        let until = record
+            .deferred_until
+            .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        println!("{:<38} {:<30} {}", contact_id, record.rule_name, until);
+    }
+    Ok(())
+}
 
-    let rule_to_apply = applicable_rules
-        .into_iter()
-        .find(|rule| inactive_duration >= Duration::hours(rule.inactive_for_hours as i64));
+/// Prints the failed deliveries stored as dead letters, most recent first.
+fn list_failed() -> Result<()> {
+    let mut letters = dead_letters::load()
+        .context("Failed to load the failed deliveries.")?
+        .entries;
+    if letters.is_empty() {
+        println!("No failed deliveries.");
+        return Ok(());
+    }
+    letters.sort_by_key(|letter| std::cmp::Reverse(letter.last_failed_at));
 
-    if let Some(rule) = rule_to_apply {
+    println!(
+        "{:<10} {:<38} {:<30} {:<16} {:<9} LAST FAILED (UTC)",
+        "ID", "CONTACT ID", "RULE", "TYPE", "FAILURES"
+    );
+    for letter in letters {
         println!(
-            "Contact has been inactive for {} hours. Applying rule: '{}'",
-            inactive_duration.num_days(),
-            rule.name
+            "{:<10} {:<38} {:<30} {:<16} {:<9} {}",
+            letter.id,
+            letter.contact_id,
+            letter.rule_name,
+            letter.payload.kind(),
+            letter.failures,
+            letter.last_failed_at.format("%Y-%m-%d %H:%M")
         );
+        println!("           to:    {}", letter.payload.target());
+        println!("           error: {}", letter.error);
+    }
+    Ok(())
+}
 
-        let wrp_contact = &contact.contact.as_ref().ok_or_else(|| {
-            anyhow!(
-                "Messaging product {} is missing contact details",
-                contact_id
-            )
-        })?;
-
-        // 5. Execute the action defined in the rule
-        match &rule.action {
-            Some(config::models::Action::WacraftMessage(action)) => {
-                let product_details = contact
-                    .product_details
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("Contact {} missing product details", contact_id))?;
-
-                let payload_base: MessagePayloadBase = action.sender_data.clone();
-                let payload = MessagePayload {
-                    base: payload_base,
-                    to: product_details.wa_id.clone(),
-                };
-
-                let message_to_send = SendWhatsAppMessage {
-                    to_id: contact_id.to_string(),
-                    sender_data: payload,
-                };
-
-                println!("Sending Wacraft message to {}...", wrp_contact.name);
-                if !mock {
-                    client.send_message(&message_to_send).await?;
-                }
-                println!("✅ Successfully sent Wacraft reminder to {}.", contact_id);
-            }
-            Some(config::models::Action::Email(action)) => {
-                println!("Sending email reminder to {}...", wrp_contact.name);
-                if !mock {
-                    email::send_reminder_email(&settings.email, &wrp_contact, action).await?;
+/// Replays stored failed deliveries. Successful ones are removed and their rule is
+/// recorded as delivered, so that it is not sent again.
+async fn retry_failed(ids: &[String], all: bool, force: bool) -> Result<()> {
+    let letters = dead_letters::load()
+        .context("Failed to load the failed deliveries.")?
+        .entries;
+    let selected: Vec<DeadLetter> = if all {
+        letters
+    } else {
+        ids.iter()
+            .map(|id| {
+                letters
+                    .iter()
+                    .find(|letter| &letter.id == id)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("No failed delivery with ID '{}'.", id))
+            })
+            .collect::<Result<_>>()?
+    };
+    if selected.is_empty() {
+        println!("No failed deliveries.");
+        return Ok(());
+    }
+
+    // Replays don't evaluate rules, so the engine needs none.
+    let settings = config::load_settings().context("Failed to load settings.json")?;
+    let engine = ReminderEngine::new(settings, Vec::new(), false);
+    let delivery_ledger = ledger::load().context("Failed to load the delivery ledger.")?;
+    let mut failed = 0;
+    // The `first_success` rules that got one of their actions delivered by a replay.
+    let mut settled = HashSet::new();
+
+    for letter in &selected {
+        let rule_key = (&letter.contact_id, &letter.rule_name, letter.activity_at);
+        if letter.first_success && settled.contains(&rule_key) {
+            println!(
+                "Skipped {}: another action of rule '{}' was delivered.",
+                letter.id, letter.rule_name
+            );
+            continue;
+        }
+        // The contact was active again since, so the reminder is out of date, and the
+        // ledger must not go back to the old episode.
+        let past_episode = delivery_ledger.is_past_episode(&letter.contact_id, letter.activity_at);
+        if past_episode && !force {
+            println!(
+                "Skipped {}: contact {} was active again since it failed. Use --force to send it anyway.",
+                letter.id, letter.contact_id
+            );
+            continue;
+        }
+        println!(
+            "Replaying {} ({}) of rule '{}' to {}...",
+            letter.id,
+            letter.payload.kind(),
+            letter.rule_name,
+            letter.payload.target()
+        );
+        let what = format!("Replay of {}", letter.id);
+        let result = retry::retry(
+            engine.settings().retry.for_action(&letter.payload),
+            &what,
+            || engine.deliver(&letter.payload),
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                println!("✅ Delivered {}.", letter.id);
+                if letter.first_success {
+                    dead_letters::resolve_rule(
+                        &letter.contact_id,
+                        &letter.rule_name,
+                        letter.activity_at,
+                    )
+                    .await?;
+                    settled.insert(rule_key);
+                } else {
+                    dead_letters::remove(&letter.id).await?;
                 }
-                println!("✅ Successfully sent email reminder to {}.", contact_id);
-            }
-            Some(config::models::Action::HttpRequest(action)) => {
-                println!("Executing HTTP request for rule '{}'...", rule.name);
-                if !mock {
-                    http_request::send_http_request(action, &wrp_contact).await?;
+                let handled = past_episode
+                    || ledger::load()?
+                        .find(&letter.contact_id, &letter.rule_name, letter.activity_at)
+                        .is_some_and(|record| record.status.is_final());
+                if !handled {
+                    ledger::record(
+                        &letter.contact_id,
+                        &letter.rule_name,
+                        letter.activity_at,
+                        DeliveryStatus::Delivered,
+                    )
+                    .await
+                    .context("Failed to record delivery in the ledger.")?;
                 }
-                println!("✅ Successfully executed HTTP request for {}.", contact_id);
             }
-            None => {
-                println!("✅ No action for {}.", contact_id);
+            Err(e) => {
+                println!("❌ Failed to deliver {}: {:#}", letter.id, e);
+                dead_letters::record_failure(&letter.id, format!("{:#}", e)).await?;
+                failed += 1;
             }
         }
-    } else {
-        info!(
-            "Contact {} is not inactive long enough for any reminder rule to apply.",
-            contact_id
-        );
     }
 
+    if failed > 0 {
+        anyhow::bail!(
+            "{} of {} failed deliveries could not be delivered.",
+            failed,
+            selected.len()
+        );
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("in2h"), "in2h");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(csv_field("Souza, Ana"), "\"Souza, Ana\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
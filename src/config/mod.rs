@@ -3,20 +3,67 @@ use anyhow::{Context, Result};
 use std::fs::{self, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 pub mod models;
 
 const CONFIG_DIR_NAME: &str = "wacraft-reminders";
 const SETTINGS_FILE_NAME: &str = "settings.json";
 const REMINDERS_FILE_NAME: &str = "reminders.json";
+const LEDGER_FILE_NAME: &str = "ledger.json";
+const TOKENS_FILE_NAME: &str = "tokens.json";
+const DEAD_LETTERS_FILE_NAME: &str = "dead_letters.json";
+const ACTIVITY_INDEX_FILE_NAME: &str = "activity_index.json";
+const PID_FILE_NAME: &str = "daemon.pid";
+const LOG_FILE_NAME: &str = "daemon.log";
+
+/// File paths given on the command line that take precedence over the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct PathOverrides {
+    pub settings: Option<PathBuf>,
+    pub reminders: Option<PathBuf>,
+}
+
+static PATH_OVERRIDES: OnceLock<PathOverrides> = OnceLock::new();
+
+/// Installs the `--settings-config` / `--reminders-config` overrides for the rest of
+/// the process. Paths are tilde-expanded and made absolute, so they stay valid for a
+/// detached daemon child. Must be called once, before any configuration is read.
+pub fn set_path_overrides(settings: Option<String>, reminders: Option<String>) -> Result<()> {
+    let resolve = |raw: Option<String>| -> Result<Option<PathBuf>> {
+        raw.map(|raw| {
+            let expanded = shellexpand::tilde(&raw).into_owned();
+            std::path::absolute(&expanded)
+                .with_context(|| format!("Failed to resolve path: {}", raw))
+        })
+        .transpose()
+    };
+    let overrides = PathOverrides {
+        settings: resolve(settings)?,
+        reminders: resolve(reminders)?,
+    };
+    PATH_OVERRIDES
+        .set(overrides)
+        .map_err(|_| anyhow::anyhow!("Configuration path overrides were already set."))
+}
+
+/// Returns the configuration path overrides in effect for this process.
+pub fn path_overrides() -> PathOverrides {
+    PATH_OVERRIDES.get().cloned().unwrap_or_default()
+}
 
 /// Returns the path to the application's configuration directory.
 /// It creates the directory if it doesn't exist.
 /// e.g., ~/.config/wacraft-reminders/ on Linux.
+/// When `--settings-config` is given, the directory holding that file is used
+/// instead, so each deployment keeps its own state files (e.g. the ledger).
 pub fn get_config_dir() -> Result<PathBuf> {
-    let config_dir = dirs::config_dir()
-        .ok_or_else(|| anyhow::anyhow!("Could not find a valid config directory."))?
-        .join(CONFIG_DIR_NAME);
+    let config_dir = match path_overrides().settings.as_deref().and_then(Path::parent) {
+        Some(parent) => parent.to_path_buf(),
+        None => dirs::config_dir()
+            .ok_or_else(|| anyhow::anyhow!("Could not find a valid config directory."))?
+            .join(CONFIG_DIR_NAME),
+    };
 
     if !config_dir.exists() {
         fs::create_dir_all(&config_dir).context("Failed to create config directory")?;
@@ -26,16 +73,52 @@ pub fn get_config_dir() -> Result<PathBuf> {
 
 /// Returns the full path to the `settings.json` file.
 pub fn get_settings_path() -> Result<PathBuf> {
-    Ok(get_config_dir()?.join(SETTINGS_FILE_NAME))
+    match path_overrides().settings {
+        Some(path) => Ok(path),
+        None => Ok(get_config_dir()?.join(SETTINGS_FILE_NAME)),
+    }
 }
 
 /// Returns the full path to the `reminders.json` file.
 pub fn get_reminders_path() -> Result<PathBuf> {
-    Ok(get_config_dir()?.join(REMINDERS_FILE_NAME))
+    match path_overrides().reminders {
+        Some(path) => Ok(path),
+        None => Ok(get_config_dir()?.join(REMINDERS_FILE_NAME)),
+    }
+}
+
+/// Returns the full path to the delivery ledger file.
+pub fn get_ledger_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(LEDGER_FILE_NAME))
+}
+
+/// Returns the full path to the failed deliveries file.
+pub fn get_dead_letters_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(DEAD_LETTERS_FILE_NAME))
+}
+
+/// Returns the full path to the local contact activity index.
+pub fn get_activity_index_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(ACTIVITY_INDEX_FILE_NAME))
+}
+
+/// Returns the full path to the cached Wacraft OAuth tokens.
+pub fn get_tokens_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(TOKENS_FILE_NAME))
+}
+
+/// Returns the full path to the PID file of the detached daemon.
+pub fn get_pid_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(PID_FILE_NAME))
+}
+
+/// Returns the full path to the log file of the detached daemon.
+pub fn get_log_path() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(LOG_FILE_NAME))
 }
 
 /// A generic function to read and deserialize a JSON file into a given type `T`.
-fn read_json_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+pub(crate) fn read_json_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
     let file = File::open(path).with_context(|| format!("Failed to open file: {:?}", path))?;
     let reader = BufReader::new(file);
     let data = serde_json::from_reader(reader)
@@ -44,28 +127,92 @@ fn read_json_file<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
 }
 
 /// A generic function to serialize a given type `T` and write it to a JSON file.
-fn write_json_file<T: ?Sized + serde::Serialize>(path: &Path, data: &T) -> Result<()> {
+/// The data is written to a temporary sibling file first and then renamed over the
+/// target, so readers never observe a half-written file.
+pub(crate) fn write_json_file<T: ?Sized + serde::Serialize>(path: &Path, data: &T) -> Result<()> {
+    write_json_file_atomically(path, data, false)
+}
+
+/// Like `write_json_file`, but the file is only accessible by the current user
+/// (mode 0600 on Unix). Used for files holding secrets, such as cached tokens.
+pub(crate) fn write_private_json_file<T: ?Sized + serde::Serialize>(
+    path: &Path,
+    data: &T,
+) -> Result<()> {
+    write_json_file_atomically(path, data, true)
+}
+
+fn write_json_file_atomically<T: ?Sized + serde::Serialize>(
+    path: &Path,
+    data: &T,
+    private: bool,
+) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.exists()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+    }
+    let tmp_path = path.with_extension("json.tmp");
     let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open(path)
-        .with_context(|| format!("Failed to create or open file for writing: {:?}", path))?;
+        .open(&tmp_path)
+        .with_context(|| format!("Failed to create or open file for writing: {:?}", tmp_path))?;
+
+    if private {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to set permissions on file: {:?}", tmp_path))?;
+        }
+    } else if let Ok(metadata) = fs::metadata(path) {
+        // Keep the permissions of the file being replaced (e.g. a 0600 settings.json).
+        fs::set_permissions(&tmp_path, metadata.permissions())
+            .with_context(|| format!("Failed to set permissions on file: {:?}", tmp_path))?;
+    }
+
     let mut writer = BufWriter::new(file);
     serde_json::to_writer_pretty(&mut writer, data)
-        .with_context(|| format!("Failed to write JSON to file: {:?}", path))?;
+        .with_context(|| format!("Failed to write JSON to file: {:?}", tmp_path))?;
     writer
         .flush()
-        .with_context(|| format!("Failed to flush writer for file: {:?}", path))?;
+        .with_context(|| format!("Failed to flush writer for file: {:?}", tmp_path))?;
+    writer
+        .get_ref()
+        .sync_all()
+        .with_context(|| format!("Failed to sync file: {:?}", tmp_path))?;
+
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move {:?} into place at {:?}", tmp_path, path))?;
     Ok(())
 }
 
+/// Takes an exclusive, cross-process lock on `<path>.lock`.
+/// The lock is released when the returned file handle is dropped.
+pub(crate) fn lock_file(path: &Path) -> Result<File> {
+    let lock_path = path.with_extension("lock");
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open lock file: {:?}", lock_path))?;
+    file.lock()
+        .with_context(|| format!("Failed to acquire lock on: {:?}", lock_path))?;
+    Ok(file)
+}
+
 // --- Public API for Configuration Management ---
 
 /// Loads the `Settings` struct from the `settings.json` file.
 pub fn load_settings() -> Result<Settings> {
     let path = get_settings_path()?;
-    read_json_file(&path)
+    let settings: Settings = read_json_file(&path)?;
+    for warning in settings.wacraft.retry.warnings() {
+        log::warn!("{}", warning);
+    }
+    Ok(settings)
 }
 
 /// Saves the `Settings` struct to the `settings.json` file.
@@ -81,7 +228,11 @@ pub fn load_reminders() -> Result<Vec<ReminderRule>> {
         // If the file doesn't exist, return an empty list.
         return Ok(Vec::new());
     }
-    read_json_file(&path)
+    let reminders: Vec<ReminderRule> = read_json_file(&path)?;
+    for warning in reminders.iter().flat_map(ReminderRule::warnings) {
+        log::warn!("{}", warning);
+    }
+    Ok(reminders)
 }
 
 /// Saves a list of reminder rules to the `reminders.json` file.
@@ -1,7 +1,15 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Datelike, Duration, DurationRound, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
+use std::str::FromStr;
 
-use crate::core::wacraft::models::MessagePayloadBase;
+use crate::core::action::RenderedAction;
+use crate::core::wacraft::components::UseTemplate;
+use crate::core::wacraft::models::{
+    CUSTOMER_SERVICE_WINDOW_HOURS, MessagePayloadBase, MessagingProductContact,
+};
 
 /// Represents the top-level structure of the `settings.json` file.
 /// It contains configurations for all external services.
@@ -9,6 +17,124 @@ use crate::core::wacraft::models::MessagePayloadBase;
 pub struct Settings {
     pub wacraft: WacraftConfig,
     pub email: EmailConfig,
+    /// How failed reminder actions are retried, by action type.
+    #[serde(default)]
+    pub retry: ActionRetryPolicies,
+}
+
+/// Retry policies for each action type.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ActionRetryPolicies {
+    pub wacraft_message: RetryPolicy,
+    pub email: RetryPolicy,
+    pub http_request: RetryPolicy,
+}
+
+impl ActionRetryPolicies {
+    /// Returns the policy for the type of a rendered action.
+    pub fn for_action(&self, action: &RenderedAction) -> &RetryPolicy {
+        match action {
+            RenderedAction::WacraftMessage(_) => &self.wacraft_message,
+            RenderedAction::Email(_) => &self.email,
+            RenderedAction::HttpRequest(_) => &self.http_request,
+        }
+    }
+}
+
+/// Retry policies for Wacraft API calls. `default` applies to every call without its
+/// own entry, e.g. `"get_conversations": { "max_attempts": 5 }`.
+///
+/// Sending messages is retried by the `wacraft_message` action policy instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ApiRetryPolicies {
+    #[serde(default)]
+    pub default: RetryPolicy,
+    #[serde(flatten)]
+    pub calls: HashMap<String, RetryPolicy>,
+}
+
+/// The Wacraft API calls that can have their own retry policy.
+pub const RETRYABLE_API_CALLS: &[&str] = &[
+    "token",
+    "get_conversations",
+    "get_conversation_messages",
+    "get_messaging_product_contact",
+];
+
+impl ApiRetryPolicies {
+    pub fn for_call(&self, call: &str) -> &RetryPolicy {
+        self.calls.get(call).unwrap_or(&self.default)
+    }
+
+    /// Returns human-readable warnings about entries that have no effect.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut calls: Vec<&String> = self.calls.keys().collect();
+        calls.sort();
+        calls
+            .into_iter()
+            .filter(|call| !RETRYABLE_API_CALLS.contains(&call.as_str()))
+            .map(|call| match call.as_str() {
+                "send_message" => "'wacraft.retry.send_message' is ignored: WhatsApp sends are \
+                    retried by the 'retry.wacraft_message' action policy."
+                    .to_string(),
+                _ => format!(
+                    "'wacraft.retry.{}' is not a Wacraft API call and is ignored. Known calls: {}.",
+                    call,
+                    RETRYABLE_API_CALLS.join(", ")
+                ),
+            })
+            .collect()
+    }
+}
+
+/// How an operation that fails transiently (connection errors, timeouts, HTTP 429
+/// and 5xx, SMTP 4xx) is retried. Permanent failures are never retried.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one. `1` disables retries.
+    pub max_attempts: u32,
+    /// The wait before the first retry.
+    pub initial_backoff_ms: u64,
+    /// The longest wait between attempts.
+    pub max_backoff_ms: u64,
+    /// How much the wait grows after each attempt.
+    pub backoff_multiplier: f64,
+    /// Randomizes each wait by up to this fraction, e.g. `0.2` for ±20%, so that
+    /// many failed sends don't all retry at once.
+    pub jitter: f64,
+    /// The longest `Retry-After` wait we honour. Servers asking for more are not
+    /// retried, rather than stalling the run.
+    pub max_retry_after_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            backoff_multiplier: 2.0,
+            jitter: 0.2,
+            max_retry_after_ms: 300_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn max_retry_after(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.max_retry_after_ms)
+    }
+
+    /// Returns the wait after the given failed attempt (starting at 1), with jitter.
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_backoff_ms as f64 * self.backoff_multiplier.powi(exponent))
+            .min(self.max_backoff_ms as f64);
+        let jitter = self.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+        std::time::Duration::from_millis((base * (1.0 + jitter)).max(0.0) as u64)
+    }
 }
 
 /// Contains the necessary settings to interact with the Wacraft API.
@@ -22,31 +148,432 @@ pub struct WacraftConfig {
     pub refresh_token: Option<String>,
     // Expiration timestamp (Unix epoch) for the access token.
     pub token_expires_at: Option<i64>,
+    /// How failed API calls are retried.
+    #[serde(default)]
+    pub retry: ApiRetryPolicies,
+    /// The most API requests sent per second, across all concurrent tasks. `0` disables the limit.
+    #[serde(default = "default_max_requests_per_second")]
+    pub max_requests_per_second: u32,
+}
+
+fn default_max_requests_per_second() -> u32 {
+    10
 }
 
 /// Contains the settings for the email service (SMTP).
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmailConfig {
+    /// How emails are delivered. The `smtp_*` fields only apply to `smtp`.
+    #[serde(default)]
+    pub transport: EmailTransport,
+    #[serde(default)]
     pub smtp_server: String,
+    #[serde(default = "default_smtp_port")]
     pub smtp_port: u16,
-    pub smtp_user: String,
-    pub smtp_password: String,
+    /// Leave the user out (or empty) for relays that don't require authentication.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_password: Option<String>,
+    /// How the connection is encrypted. Inferred from `smtp_port` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp_tls: Option<SmtpTls>,
+    /// Timeout for connecting to and talking with the SMTP server.
+    #[serde(default = "default_smtp_timeout_secs")]
+    pub smtp_timeout_secs: u64,
+    /// The most SMTP connections kept open at once.
+    #[serde(default = "default_smtp_max_connections")]
+    pub smtp_max_connections: u32,
+    /// Spaces out emails to send at most this many per minute. Unlimited when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages_per_minute: Option<u32>,
+    /// The sendmail binary for the `sendmail` transport. Defaults to `sendmail` on the `PATH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sendmail_command: Option<String>,
+    /// The directory the `file` transport writes `.eml` files into.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<String>,
     pub from_address: String,
 }
 
+fn default_smtp_port() -> u16 {
+    587
+}
+
+fn default_smtp_timeout_secs() -> u64 {
+    30
+}
+
+fn default_smtp_max_connections() -> u32 {
+    10
+}
+
+/// How emails are delivered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailTransport {
+    /// Through an SMTP server.
+    #[default]
+    Smtp,
+    /// By piping the message to a local sendmail-compatible binary.
+    Sendmail,
+    /// By writing each message as an `.eml` file into `output_dir`, e.g. for CI.
+    File,
+}
+
+impl EmailConfig {
+    /// Returns the TLS mode to use: the configured one, or `implicit` on port 465
+    /// and `starttls` on any other port.
+    pub fn tls_mode(&self) -> SmtpTls {
+        self.smtp_tls.unwrap_or(match self.smtp_port {
+            465 => SmtpTls::Implicit,
+            _ => SmtpTls::Starttls,
+        })
+    }
+}
+
+/// How the SMTP connection is encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// TLS from the start of the connection, usually on port 465.
+    Implicit,
+    /// Upgrade a plain connection with STARTTLS, usually on port 587. The upgrade is required.
+    Starttls,
+    /// No encryption, e.g. for local test catchers such as MailHog on port 1025.
+    None,
+}
+
 /// Details for the action of sending a Wacraft message.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WacraftMessageAction {
     // #[serde(flatten)]
     pub sender_data: MessagePayloadBase,
+    /// Template sent instead of a free-form `sender_data` message when the contact's
+    /// 24-hour customer-service window is closed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_template: Option<UseTemplate>,
 }
 
 /// Represents a single rule in the `reminders.json` file.
+/// Rules are steps of a sequence: steps sharing a `sequence` name fire once each,
+/// in `inactive_for_hours` order, and the sequence restarts with new activity.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ReminderRule {
     pub name: String,
     pub inactive_for_hours: u64,
     pub action: Option<Action>,
+    /// Further actions for this rule, run after `action` in the order listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<Action>,
+    /// Whether to run every action or stop at the first one that succeeds.
+    #[serde(default)]
+    pub action_mode: ActionMode,
+    /// Which messages count as activity when measuring inactivity for this rule.
+    #[serde(default)]
+    pub inactivity_basis: InactivityBasis,
+    /// Restricts the rule to contacts matching these filters. Other contacts skip it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audience: Option<AudienceFilter>,
+    /// Restricts when the rule may send. Due reminders wait for the next allowed slot.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sending_window: Option<SendingWindow>,
+    /// A cron expression for when the daemon evaluates this rule, overriding the
+    /// daemon's own `--interval`/`--schedule`. Manual runs ignore it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<CronSchedule>,
+    /// The sequence this rule belongs to. Rules without one share the default sequence.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<String>,
+    /// Names of earlier steps that must have been delivered in the current
+    /// inactivity episode for this step to run. Otherwise the step is skipped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+}
+
+/// Filters that select which contacts a rule applies to.
+/// Every filter that is set must match; unset filters match everyone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AudienceFilter {
+    /// Only match contacts whose `blocked` flag has this value. Missing counts as not blocked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked: Option<bool>,
+    /// Only match contacts on one of these messaging products.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messaging_product_ids: Vec<String>,
+    /// Only match contacts that have (`true`) or lack (`false`) an email address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_email: Option<bool>,
+    /// Only match contacts created at least this many days ago.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_contact_age_days: Option<u64>,
+    /// Only match contacts created at most this many days ago.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_contact_age_days: Option<u64>,
+    /// Only match contacts whose name matches this regular expression.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_regex: Option<Pattern>,
+    /// Only match contacts whose phone number matches this regular expression.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone_regex: Option<Pattern>,
+}
+
+impl AudienceFilter {
+    /// Returns whether the contact belongs to this audience.
+    pub fn matches(&self, contact: &MessagingProductContact, now: DateTime<Utc>) -> bool {
+        if let Some(blocked) = self.blocked
+            && contact.blocked.unwrap_or(false) != blocked
+        {
+            return false;
+        }
+        if !self.messaging_product_ids.is_empty()
+            && !contact
+                .messaging_product_id
+                .as_ref()
+                .is_some_and(|id| self.messaging_product_ids.contains(id))
+        {
+            return false;
+        }
+
+        let details = contact.contact.as_ref();
+        if let Some(has_email) = self.has_email
+            && details
+                .and_then(|c| c.email.as_deref())
+                .is_some_and(|e| !e.is_empty())
+                != has_email
+        {
+            return false;
+        }
+
+        let age = details.map(|c| now.signed_duration_since(c.created_at));
+        if let Some(min_days) = self.min_contact_age_days
+            && age.is_none_or(|age| age < Duration::days(min_days as i64))
+        {
+            return false;
+        }
+        if let Some(max_days) = self.max_contact_age_days
+            && age.is_none_or(|age| age > Duration::days(max_days as i64))
+        {
+            return false;
+        }
+
+        if let Some(pattern) = &self.name_regex
+            && !details.is_some_and(|c| pattern.0.is_match(&c.name))
+        {
+            return false;
+        }
+        if let Some(pattern) = &self.phone_regex
+            && !contact
+                .product_details
+                .as_ref()
+                .is_some_and(|p| pattern.0.is_match(&p.phone_number))
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Days and hours, in a given timezone, during which a rule may send reminders.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendingWindow {
+    /// IANA timezone name the window is expressed in, e.g. "America/Sao_Paulo".
+    pub timezone: Tz,
+    /// Allowed weekdays, e.g. `["Mon", "Tue"]`. Empty allows every day.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weekdays: Vec<Weekday>,
+    /// Allowed local hour ranges. Empty allows the whole day.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hours: Vec<HourRange>,
+}
+
+/// A range of local hours, from `start` (inclusive) to `end` (exclusive), 0-24.
+/// A range with `start` after `end` wraps past midnight, e.g. 22 to 6.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct HourRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl HourRange {
+    fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+impl SendingWindow {
+    /// Returns whether reminders may be sent at `at`.
+    pub fn is_open(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let day_allowed = self.weekdays.is_empty() || self.weekdays.contains(&local.weekday());
+        let hour_allowed =
+            self.hours.is_empty() || self.hours.iter().any(|r| r.contains(local.hour()));
+        day_allowed && hour_allowed
+    }
+
+    /// Returns the earliest time at or after `from` when the window is open,
+    /// or `None` if it never opens within the next week.
+    pub fn next_open(&self, from: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.is_open(from) {
+            return Some(from);
+        }
+        // Windows are made of whole local hours, so only hour boundaries need checking.
+        let local = from.with_timezone(&self.timezone);
+        let mut candidate = local.duration_trunc(Duration::hours(1)).ok()? + Duration::hours(1);
+        for _ in 0..(8 * 24) {
+            let candidate_utc = candidate.with_timezone(&Utc);
+            if self.is_open(candidate_utc) {
+                return Some(candidate_utc);
+            }
+            candidate += Duration::hours(1);
+        }
+        None
+    }
+}
+
+/// A cron expression, parsed when it is loaded.
+/// Both the standard five-field form ("0 9 * * Mon-Fri") and the six-field form with
+/// seconds ("0 0 9 * * Mon-Fri") are accepted.
+#[derive(Debug, Clone)]
+pub struct CronSchedule(pub Box<cron::Schedule>);
+
+impl CronSchedule {
+    /// Returns the first fire time strictly after `after`, evaluated in `timezone`.
+    pub fn next_after(&self, after: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        self.0
+            .after(&after.with_timezone(&timezone))
+            .next()
+            .map(|at| at.with_timezone(&Utc))
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let expression = if raw.split_whitespace().count() == 5 {
+            format!("0 {}", raw)
+        } else {
+            raw.to_string()
+        };
+        cron::Schedule::from_str(&expression)
+            .map(|schedule| CronSchedule(Box::new(schedule)))
+            .map_err(|e| format!("invalid cron expression '{}': {}", raw, e))
+    }
+}
+
+impl Serialize for CronSchedule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.source())
+    }
+}
+
+impl<'de> Deserialize<'de> for CronSchedule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A regular expression that is compiled when `reminders.json` is loaded,
+/// so an invalid pattern is reported up front rather than per contact.
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        Regex::new(&raw)
+            .map(Pattern)
+            .map_err(|e| serde::de::Error::custom(format!("invalid regex '{}': {}", raw, e)))
+    }
+}
+
+/// How the actions of a rule with several actions are run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionMode {
+    /// Run every action, even if some of them fail.
+    #[default]
+    All,
+    /// Run actions in order until one succeeds, e.g. to fall back from WhatsApp to email.
+    FirstSuccess,
+}
+
+/// Which messages reset a contact's inactivity clock.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InactivityBasis {
+    /// Time since the contact last wrote to us. Our own reminders don't reset it.
+    #[default]
+    Inbound,
+    /// Time since we last wrote to the contact.
+    Outbound,
+    /// Time since the last message in either direction.
+    Any,
+}
+
+impl ReminderRule {
+    pub const DEFAULT_SEQUENCE: &'static str = "default";
+
+    /// Returns the name of the sequence this rule is a step of.
+    pub fn sequence_name(&self) -> &str {
+        self.sequence.as_deref().unwrap_or(Self::DEFAULT_SEQUENCE)
+    }
+
+    /// Returns the rule's actions in the order they run: `action` first, then `actions`.
+    pub fn all_actions(&self) -> impl Iterator<Item = &Action> {
+        self.action.iter().chain(&self.actions)
+    }
+
+    /// Returns problems with the rule that won't stop it from loading but will make
+    /// it misbehave at send time.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        // WhatsApp only accepts free-form messages within 24h of the contact's
+        // last inbound message.
+        for action in self.all_actions() {
+            let Action::WacraftMessage(action) = action else {
+                continue;
+            };
+            if !action.sender_data.is_free_form() || action.fallback_template.is_some() {
+                continue;
+            }
+            let always_outside = self.inactivity_basis == InactivityBasis::Inbound
+                && self.inactive_for_hours >= CUSTOMER_SERVICE_WINDOW_HOURS as u64;
+            warnings.push(format!(
+                "Rule '{}' sends a free-form '{}' WhatsApp message that {} outside the \
+                 24-hour customer-service window. Add a 'fallback_template'.",
+                self.name,
+                action.sender_data.message_type,
+                if always_outside {
+                    "will always be"
+                } else {
+                    "may be"
+                },
+            ));
+        }
+        if let Some(window) = &self.sending_window
+            && window.next_open(Utc::now()).is_none()
+        {
+            warnings.push(format!(
+                "Rule '{}' has a sending window that never opens. Hours must be between 0 and 24.",
+                self.name
+            ));
+        }
+        warnings
+    }
 }
 
 /// An enum representing the different types of actions that can be taken for a reminder.
@@ -56,17 +583,22 @@ pub struct ReminderRule {
 #[serde(tag = "type")]
 pub enum Action {
     #[serde(rename = "wacraft_message")]
-    WacraftMessage(WacraftMessageAction),
+    WacraftMessage(Box<WacraftMessageAction>),
     #[serde(rename = "email")]
     Email(EmailAction),
     #[serde(rename = "http_request")]
     HttpRequest(HttpRequestAction),
 }
 
-/// Details for the action of sending a Wacraft message.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SendWhatsAppMessage {
-    // Generate from documentation
+impl Action {
+    /// Returns the action's `type` tag, used to name the channel in output and logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::WacraftMessage(_) => "wacraft_message",
+            Action::Email(_) => "email",
+            Action::HttpRequest(_) => "http_request",
+        }
+    }
 }
 
 /// Details for the action of sending an email.
@@ -75,6 +607,33 @@ pub struct EmailAction {
     pub subject: String,
     /// Path to the email template file (e.g., an HTML file).
     pub template: String,
+    /// Path to a plain-text template. When set, the email is sent as
+    /// multipart/alternative with both the text and the HTML version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_template: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<EmailAttachment>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Extra headers, e.g. `List-Unsubscribe`. Values are templates.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+/// A file attached to an email.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailAttachment {
+    pub path: String,
+    /// The file name shown to the recipient. Defaults to the file's own name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Defaults to `application/octet-stream`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
 }
 
 /// Details for the action of making an HTTP request (e.g., to a webhook).
@@ -89,3 +648,105 @@ pub struct HttpRequestAction {
     #[serde(default)]
     pub body: serde_json::Value,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{contact, march};
+
+    /// Weekdays from 9:00 to 18:00 in São Paulo, which is UTC-3.
+    fn office_hours() -> SendingWindow {
+        serde_json::from_str(
+            r#"{
+                "timezone": "America/Sao_Paulo",
+                "weekdays": ["Mon", "Tue", "Wed", "Thu", "Fri"],
+                "hours": [{"start": 9, "end": 18}]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn sending_window_uses_local_time() {
+        let window = office_hours();
+        // 2026-03-09 is a Monday.
+        assert!(!window.is_open(march(9, 11, 59)));
+        assert!(window.is_open(march(9, 12, 0)));
+        assert!(window.is_open(march(9, 20, 59)));
+        assert!(!window.is_open(march(9, 21, 0)));
+        assert!(!window.is_open(march(14, 15, 0)));
+    }
+
+    #[test]
+    fn sending_window_next_open() {
+        let window = office_hours();
+        assert_eq!(window.next_open(march(9, 13, 30)), Some(march(9, 13, 30)));
+        assert_eq!(window.next_open(march(9, 10, 15)), Some(march(9, 12, 0)));
+        // From Friday evening to Monday morning.
+        assert_eq!(window.next_open(march(13, 22, 0)), Some(march(16, 12, 0)));
+    }
+
+    #[test]
+    fn sending_window_hours_wrap_past_midnight() {
+        let window = SendingWindow {
+            timezone: Tz::UTC,
+            weekdays: Vec::new(),
+            hours: vec![HourRange { start: 22, end: 6 }],
+        };
+        assert!(window.is_open(march(9, 23, 0)));
+        assert!(window.is_open(march(9, 5, 59)));
+        assert!(!window.is_open(march(9, 6, 0)));
+        assert_eq!(window.next_open(march(9, 12, 0)), Some(march(9, 22, 0)));
+    }
+
+    #[test]
+    fn sending_window_that_never_opens() {
+        let window = SendingWindow {
+            timezone: Tz::UTC,
+            weekdays: Vec::new(),
+            hours: vec![HourRange { start: 9, end: 9 }],
+        };
+        assert_eq!(window.next_open(march(9, 12, 0)), None);
+    }
+
+    fn audience(json: &str) -> AudienceFilter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn empty_audience_matches_everyone() {
+        let now = march(9, 12, 0);
+        assert!(AudienceFilter::default().matches(&contact(None, now), now));
+    }
+
+    #[test]
+    fn audience_filters_must_all_match() {
+        let now = march(30, 12, 0);
+        let ana = contact(Some("ana@example.com"), march(9, 12, 0));
+        let filter = audience(
+            r#"{"has_email": true, "min_contact_age_days": 7, "name_regex": "^Ana ", "phone_regex": "^\\+55"}"#,
+        );
+        assert!(filter.matches(&ana, now));
+        // Too recent for min_contact_age_days.
+        assert!(!filter.matches(&ana, march(10, 12, 0)));
+        assert!(!filter.matches(&contact(None, march(9, 12, 0)), now));
+        assert!(!audience(r#"{"name_regex": "^Bia"}"#).matches(&ana, now));
+    }
+
+    #[test]
+    fn audience_checks_blocked_and_product() {
+        let now = march(9, 12, 0);
+        let mut ana = contact(Some(""), now);
+        // A blank email counts as none, and a missing flag as not blocked.
+        assert!(audience(r#"{"has_email": false, "blocked": false}"#).matches(&ana, now));
+        ana.blocked = Some(true);
+        assert!(!audience(r#"{"blocked": false}"#).matches(&ana, now));
+        assert!(audience(r#"{"messaging_product_ids": ["whatsapp"]}"#).matches(&ana, now));
+        assert!(!audience(r#"{"messaging_product_ids": ["telegram"]}"#).matches(&ana, now));
+    }
+
+    #[test]
+    fn audience_rejects_invalid_regex() {
+        assert!(serde_json::from_str::<AudienceFilter>(r#"{"name_regex": "("}"#).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use crate::core::email::{Mailer, RenderedEmail};
+use crate::core::http_request::{self, RenderedHttpRequest};
+use crate::core::wacraft::client::WacraftClient;
+use crate::core::wacraft::models::SendWhatsAppMessage;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// A reminder action rendered for one contact, with every template filled in.
+///
+/// Actions are rendered first and delivered second, so that a delivery that keeps
+/// failing can be stored and replayed later exactly as it was first attempted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RenderedAction {
+    WacraftMessage(Box<SendWhatsAppMessage>),
+    Email(RenderedEmail),
+    HttpRequest(RenderedHttpRequest),
+}
+
+impl RenderedAction {
+    /// Returns the action's `type` tag, matching `Action::kind`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RenderedAction::WacraftMessage(_) => "wacraft_message",
+            RenderedAction::Email(_) => "email",
+            RenderedAction::HttpRequest(_) => "http_request",
+        }
+    }
+
+    /// Describes where the action goes, for listings.
+    pub fn target(&self) -> String {
+        match self {
+            RenderedAction::WacraftMessage(message) => {
+                format!("WhatsApp {}", message.sender_data.to)
+            }
+            RenderedAction::Email(email) => email.recipients.join(", "),
+            RenderedAction::HttpRequest(request) => {
+                format!("{} {}", request.method.to_uppercase(), request.url)
+            }
+        }
+    }
+
+    /// Delivers the action through its channel.
+    pub async fn deliver(
+        &self,
+        client: &WacraftClient,
+        mailer: &Mailer,
+        http_client: &reqwest::Client,
+    ) -> Result<()> {
+        match self {
+            RenderedAction::WacraftMessage(message) => client.send_message(message).await,
+            RenderedAction::Email(email) => mailer.send(email).await.with_context(|| {
+                format!("Failed to send email to '{}'", email.recipients.join(", "))
+            }),
+            RenderedAction::HttpRequest(request) => {
+                http_request::send_http_request(http_client, request).await
+            }
+        }
+    }
+}
@@ -1,54 +1,265 @@
-use crate::config::models::{EmailAction, EmailConfig};
-use crate::core::wacraft::models::Contact;
-use anyhow::{Context, Result};
+use crate::config::models::{EmailAction, EmailAttachment, EmailConfig, EmailTransport, SmtpTls};
+use crate::core::template::{Escape, TemplateContext};
+use crate::core::throttle::Throttle;
+use anyhow::{Context, Result, anyhow};
+use lettre::address::Envelope;
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::PoolConfig;
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{
+    AsyncFileTransport, AsyncSendmailTransport, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::OnceCell;
 
-/// Sends a reminder email to a contact based on a rule.
-pub async fn send_reminder_email(
+/// An email rendered for a contact, ready to be sent: the formatted message and the
+/// envelope addresses, which include cc and bcc recipients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderedEmail {
+    pub from: String,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    /// The full RFC 5322 message.
+    pub message: String,
+}
+
+/// Renders a reminder email for a contact based on a rule.
+pub fn render_reminder_email(
+    email_config: &EmailConfig,
+    ctx: &TemplateContext<'_>,
+    action: &EmailAction,
+) -> Result<RenderedEmail> {
+    let subject = ctx.render(&action.subject, Escape::None)?;
+    let email = build_message(email_config, ctx, action)?;
+    let envelope = email.envelope();
+    Ok(RenderedEmail {
+        from: envelope.from().map(ToString::to_string).unwrap_or_default(),
+        recipients: envelope.to().iter().map(ToString::to_string).collect(),
+        subject,
+        message: String::from_utf8_lossy(&email.formatted()).into_owned(),
+    })
+}
+
+/// Builds the email for a contact, rendering the subject, bodies and header values.
+fn build_message(
     email_config: &EmailConfig,
-    contact: &Contact,
+    ctx: &TemplateContext<'_>,
     action: &EmailAction,
-) -> Result<()> {
+) -> Result<Message> {
+    let contact = ctx.contact;
     // Ensure the contact has an email address.
     let recipient_email = contact
         .email
         .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Contact '{}' has no email address.", contact.name))?;
+        .ok_or_else(|| anyhow!("Contact '{}' has no email address.", contact.name))?;
 
-    // 1. Read and prepare the email template.
-    let template_content = fs::read_to_string(&action.template)
-        .with_context(|| format!("Failed to read email template from '{}'", action.template))?;
+    // Render the subject as plain text, the HTML body with HTML escaping and the
+    // optional text body as plain text.
+    let subject = ctx.render(&action.subject, Escape::None)?;
+    let html_body = ctx.render(&read_template(&action.template)?, Escape::Html)?;
+    let text_body = action
+        .text_template
+        .as_deref()
+        .map(|path| ctx.render(&read_template(path)?, Escape::None))
+        .transpose()?;
 
-    // Perform simple placeholder replacement.
-    let email_body = template_content.replace("{contact_name}", &contact.name);
-
-    // 2. Build the email message.
-    let email = Message::builder()
+    let mut builder = Message::builder()
         .from(email_config.from_address.parse()?)
-        .to(recipient_email.parse()?)
-        .subject(&action.subject)
-        .header(lettre::message::header::ContentType::TEXT_HTML)
-        .body(email_body)?;
-
-    // 3. Configure the SMTP transport.
-    let creds = Credentials::new(
-        email_config.smtp_user.clone(),
-        email_config.smtp_password.clone(),
-    );
-
-    // Build the mailer transport.
-    let mailer = SmtpTransport::relay(&email_config.smtp_server)?
-        .credentials(creds)
-        .build();
-
-    // 4. Send the email.
-    // The `send` method is synchronous, but we run it in a blocking task
-    // to avoid blocking the async runtime.
-    tokio::task::spawn_blocking(move || mailer.send(&email))
-        .await? // Wait for the blocking task to complete
-        .with_context(|| format!("Failed to send email to '{}'", recipient_email))?;
-
-    Ok(())
+        .to(parse_mailbox(recipient_email)?)
+        .subject(subject);
+    for address in &action.cc {
+        builder = builder.cc(parse_mailbox(&ctx.render(address, Escape::None)?)?);
+    }
+    for address in &action.bcc {
+        builder = builder.bcc(parse_mailbox(&ctx.render(address, Escape::None)?)?);
+    }
+    if let Some(address) = &action.reply_to {
+        builder = builder.reply_to(parse_mailbox(&ctx.render(address, Escape::None)?)?);
+    }
+
+    // HTML only, or multipart/alternative with a plain-text version, wrapped in
+    // multipart/mixed when there are attachments.
+    let mut email = match (text_body, action.attachments.is_empty()) {
+        (None, true) => builder.singlepart(SinglePart::html(html_body))?,
+        (Some(text_body), true) => {
+            builder.multipart(MultiPart::alternative_plain_html(text_body, html_body))?
+        }
+        (text_body, false) => {
+            let mut mixed = match text_body {
+                Some(text_body) => MultiPart::mixed()
+                    .multipart(MultiPart::alternative_plain_html(text_body, html_body)),
+                None => MultiPart::mixed().singlepart(SinglePart::html(html_body)),
+            };
+            for attachment in &action.attachments {
+                mixed = mixed.singlepart(build_attachment(attachment)?);
+            }
+            builder.multipart(mixed)?
+        }
+    };
+
+    for (name, value) in &action.headers {
+        let header_name = HeaderName::new_from_ascii(name.clone())
+            .map_err(|_| anyhow!("Invalid email header name: '{}'", name))?;
+        email.headers_mut().insert_raw(HeaderValue::new(
+            header_name,
+            ctx.render(value, Escape::None)?,
+        ));
+    }
+
+    Ok(email)
+}
+
+fn read_template(path: &str) -> Result<String> {
+    fs::read_to_string(path)
+        .with_context(|| format!("Failed to read email template from '{}'", path))
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .with_context(|| format!("Invalid email address: '{}'", address))
+}
+
+/// Reads a file and wraps it as an attachment part.
+fn build_attachment(attachment: &EmailAttachment) -> Result<SinglePart> {
+    let content = fs::read(&attachment.path)
+        .with_context(|| format!("Failed to read attachment '{}'", attachment.path))?;
+    let filename = match &attachment.filename {
+        Some(filename) => filename.clone(),
+        None => Path::new(&attachment.path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| attachment.path.clone()),
+    };
+    let content_type = attachment
+        .content_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    let content_type = ContentType::parse(content_type)
+        .map_err(|_| anyhow!("Invalid attachment content type: '{}'", content_type))?;
+    Ok(Attachment::new(filename).body(content, content_type))
+}
+
+/// Delivers emails through the configured transport.
+///
+/// A mailer is meant to be created once and shared by every email of a run: SMTP
+/// connections are pooled and reused instead of being opened (and TLS-negotiated)
+/// for each message. The transport is only built when the first email is sent, so
+/// setups without email actions don't need a valid email configuration.
+pub struct Mailer {
+    config: EmailConfig,
+    transport: OnceCell<Transport>,
+    throttle: Option<Throttle>,
+}
+
+enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    Sendmail(AsyncSendmailTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+}
+
+impl Mailer {
+    pub fn new(config: &EmailConfig) -> Self {
+        let throttle = config
+            .max_messages_per_minute
+            .and_then(|limit| Throttle::new(limit, Duration::from_secs(60)));
+        Self {
+            config: config.clone(),
+            transport: OnceCell::new(),
+            throttle,
+        }
+    }
+
+    /// Sends an email, waiting for a free slot first if a rate limit is configured.
+    pub async fn send(&self, email: &RenderedEmail) -> Result<()> {
+        let envelope = Envelope::new(
+            Some(email.from.parse()?),
+            email
+                .recipients
+                .iter()
+                .map(|recipient| recipient.parse())
+                .collect::<Result<_, _>>()?,
+        )?;
+        let transport = self
+            .transport
+            .get_or_try_init(|| async { Transport::new(&self.config) })
+            .await?;
+        if let Some(throttle) = &self.throttle {
+            throttle.wait().await;
+        }
+        let message = email.message.as_bytes();
+        match transport {
+            Transport::Smtp(transport) => {
+                transport.send_raw(&envelope, message).await?;
+            }
+            Transport::Sendmail(transport) => {
+                transport.send_raw(&envelope, message).await?;
+            }
+            Transport::File(transport) => {
+                transport.send_raw(&envelope, message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport {
+    fn new(email_config: &EmailConfig) -> Result<Self> {
+        Ok(match email_config.transport {
+            EmailTransport::Smtp => Transport::Smtp(build_smtp_transport(email_config)?),
+            EmailTransport::Sendmail => Transport::Sendmail(match &email_config.sendmail_command {
+                Some(command) => {
+                    AsyncSendmailTransport::new_with_command(shellexpand::tilde(command).as_ref())
+                }
+                None => AsyncSendmailTransport::new(),
+            }),
+            EmailTransport::File => {
+                let dir = email_config
+                    .output_dir
+                    .as_deref()
+                    .ok_or_else(|| anyhow!("The 'file' email transport requires 'output_dir'"))?;
+                let dir = shellexpand::tilde(dir).into_owned();
+                fs::create_dir_all(&dir).with_context(|| {
+                    format!("Failed to create email output directory '{}'", dir)
+                })?;
+                Transport::File(AsyncFileTransport::new(dir))
+            }
+        })
+    }
+}
+
+/// Builds a pooled SMTP transport for the configured server, port, TLS mode and credentials.
+fn build_smtp_transport(email_config: &EmailConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+    if email_config.smtp_server.is_empty() {
+        anyhow::bail!("The 'smtp' email transport requires 'smtp_server'");
+    }
+
+    let tls = match email_config.tls_mode() {
+        SmtpTls::Implicit => Tls::Wrapper(TlsParameters::new(email_config.smtp_server.clone())?),
+        SmtpTls::Starttls => Tls::Required(TlsParameters::new(email_config.smtp_server.clone())?),
+        SmtpTls::None => Tls::None,
+    };
+
+    let mut builder =
+        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&email_config.smtp_server)
+            .port(email_config.smtp_port)
+            .tls(tls)
+            .timeout(Some(Duration::from_secs(email_config.smtp_timeout_secs)))
+            .pool_config(PoolConfig::new().max_size(email_config.smtp_max_connections));
+
+    // Only authenticate when a user is configured.
+    if let Some(user) = email_config.smtp_user.as_ref().filter(|u| !u.is_empty()) {
+        builder = builder.credentials(Credentials::new(
+            user.clone(),
+            email_config.smtp_password.clone().unwrap_or_default(),
+        ));
+    }
+
+    Ok(builder.build())
 }
@@ -0,0 +1,1177 @@
+use crate::config::{
+    self,
+    models::{Action, ActionMode, ReminderRule, SendingWindow, Settings},
+};
+use crate::core::action::RenderedAction;
+use crate::core::email::{self, Mailer};
+use crate::core::template::TemplateContext;
+use crate::core::wacraft::models::{
+    ContactActivity, Conversation, MessagePayloadBase, MessagingProductContact,
+};
+use crate::core::wacraft::{
+    client::WacraftClient,
+    models::{MessagePayload, SendWhatsAppMessage},
+};
+use crate::core::{http_request, retry};
+use crate::store::activity_index::IndexedContact;
+use crate::store::dead_letters;
+use crate::store::ledger::{self, DeliveryStatus, Ledger};
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use strum_macros::Display;
+
+/// Where a contact stands in one reminder sequence.
+struct SequencePlan<'r> {
+    /// Steps passed over because the steps they require were not delivered, with
+    /// the missing ones.
+    skipped: Vec<(&'r ReminderRule, Vec<&'r str>)>,
+    next: NextStep<'r>,
+}
+
+/// The step of a sequence that runs or becomes due next, and why it waits.
+enum NextStep<'r> {
+    /// The step is due and runs now. `since` is the activity it is measured from.
+    Due {
+        step: &'r ReminderRule,
+        since: DateTime<Utc>,
+    },
+    /// The step is due but outside its sending window, which opens at `until`.
+    Deferred {
+        step: &'r ReminderRule,
+        window: &'r SendingWindow,
+        until: Option<DateTime<Utc>>,
+    },
+    /// The step is due but waits for its own schedule to fire.
+    Scheduled { step: &'r ReminderRule },
+    /// The step becomes due at `due_at`.
+    Waiting {
+        step: &'r ReminderRule,
+        due_at: DateTime<Utc>,
+    },
+    /// The contact has no messages in the direction the step measures from.
+    NoActivity { step: &'r ReminderRule },
+    /// Every step of the sequence is handled for this episode, or doesn't apply.
+    Done,
+}
+
+impl<'r> NextStep<'r> {
+    /// The step this outcome is about, if any.
+    fn step(&self) -> Option<&'r ReminderRule> {
+        match *self {
+            NextStep::Due { step, .. }
+            | NextStep::Deferred { step, .. }
+            | NextStep::Scheduled { step }
+            | NextStep::Waiting { step, .. }
+            | NextStep::NoActivity { step } => Some(step),
+            NextStep::Done => None,
+        }
+    }
+
+    fn status(&self) -> PlanStatus {
+        match self {
+            NextStep::Due { .. } => PlanStatus::Due,
+            NextStep::Deferred { .. } => PlanStatus::Deferred,
+            NextStep::Scheduled { .. } => PlanStatus::Scheduled,
+            NextStep::Waiting { .. } => PlanStatus::Waiting,
+            NextStep::NoActivity { .. } => PlanStatus::NoActivity,
+            NextStep::Done => PlanStatus::Done,
+        }
+    }
+
+    /// When the step became or becomes due. Deferred steps give the time their window
+    /// opens, and scheduled ones the next time their schedule fires in `timezone`.
+    fn due_at(&self, now: DateTime<Utc>, timezone: Tz) -> Option<DateTime<Utc>> {
+        match self {
+            NextStep::Due { step, since } => {
+                Some(*since + Duration::hours(step.inactive_for_hours as i64))
+            }
+            NextStep::Deferred { until, .. } => *until,
+            NextStep::Scheduled { step } => step
+                .schedule
+                .as_ref()
+                .and_then(|schedule| schedule.next_after(now, timezone)),
+            NextStep::Waiting { due_at, .. } => Some(*due_at),
+            NextStep::NoActivity { .. } | NextStep::Done => None,
+        }
+    }
+}
+
+/// A contact's standing in one reminder sequence, as reported by `reminders plan`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum PlanStatus {
+    /// The rule would run now.
+    Due,
+    /// The rule is due but waits for its sending window.
+    Deferred,
+    /// The rule is due but waits for its own schedule.
+    Scheduled,
+    /// The rule is not due yet.
+    Waiting,
+    /// The contact has no messages in the direction the rule measures from.
+    NoActivity,
+    /// Every rule of the sequence is handled for this inactivity episode.
+    Done,
+    /// The contact could not be evaluated.
+    Error,
+}
+
+/// An action of a planned rule, rendered for the contact but not delivered.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedAction {
+    pub kind: &'static str,
+    /// Where the action would go, e.g. a phone number, email recipients or a URL.
+    pub target: Option<String>,
+    /// Why the action could not be rendered, such as a template error.
+    pub error: Option<String>,
+}
+
+/// What the engine would do next for a contact in one reminder sequence.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlanEntry {
+    pub contact_id: String,
+    pub contact_name: Option<String>,
+    pub sequence: Option<String>,
+    /// The last message the rule measures inactivity from (inbound when there is no rule).
+    pub inactive_since: Option<DateTime<Utc>>,
+    pub inactive_hours: Option<i64>,
+    pub status: PlanStatus,
+    /// The rule that runs now, waits, or becomes due next.
+    pub rule: Option<String>,
+    /// When `rule` became or becomes due. For deferred and scheduled rules, when
+    /// they may run.
+    pub due_at: Option<DateTime<Utc>>,
+    pub actions: Vec<PlannedAction>,
+    /// For rules that are due, the rule after it in the sequence.
+    pub next_rule: Option<String>,
+    pub next_due_at: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+}
+
+impl PlanEntry {
+    /// An entry for a contact that could not be evaluated.
+    pub fn failed(contact_id: &str, error: &anyhow::Error) -> Self {
+        Self {
+            contact_id: contact_id.to_string(),
+            contact_name: None,
+            sequence: None,
+            inactive_since: None,
+            inactive_hours: None,
+            status: PlanStatus::Error,
+            rule: None,
+            due_at: None,
+            actions: Vec::new(),
+            next_rule: None,
+            next_due_at: None,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+}
+
+/// Evaluates reminder rules for contacts and delivers the due reminders.
+///
+/// An engine is built once per run (a daemon cycle or a CLI command) and shared by
+/// every contact of that run: the rules are parsed once, the Wacraft client keeps
+/// its access token between contacts, and the mailer and HTTP client reuse their
+/// connections.
+pub struct ReminderEngine {
+    settings: Settings,
+    /// The rules, grouped into ordered sequences.
+    sequences: Vec<(String, Vec<ReminderRule>)>,
+    client: WacraftClient,
+    mailer: Mailer,
+    http_client: reqwest::Client,
+    /// Skips actual sending and recording, for testing.
+    mock: bool,
+}
+
+/// Which rules may run during an evaluation.
+#[derive(Debug, Clone)]
+pub enum RuleSelection {
+    /// Every rule, regardless of its schedule. Used for manual runs.
+    All,
+    /// The rules whose schedule fired: those without their own schedule when
+    /// `default` is set, plus the named rules with their own schedule.
+    Scheduled {
+        default: bool,
+        rules: HashSet<String>,
+    },
+}
+
+impl RuleSelection {
+    /// Returns whether `rule` may run in this evaluation.
+    pub fn includes(&self, rule: &ReminderRule) -> bool {
+        match self {
+            RuleSelection::All => true,
+            RuleSelection::Scheduled { default, rules } => match rule.schedule {
+                Some(_) => rules.contains(&rule.name),
+                None => *default,
+            },
+        }
+    }
+}
+
+impl fmt::Display for RuleSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleSelection::All => write!(f, "all rules"),
+            RuleSelection::Scheduled { default, rules } => {
+                let mut parts: Vec<String> = rules.iter().map(|r| format!("'{}'", r)).collect();
+                parts.sort();
+                if *default {
+                    parts.insert(0, "unscheduled rules".to_string());
+                }
+                write!(f, "{}", parts.join(", "))
+            }
+        }
+    }
+}
+
+impl ReminderEngine {
+    pub fn new(settings: Settings, rules: Vec<ReminderRule>, mock: bool) -> Self {
+        Self {
+            client: WacraftClient::new(settings.wacraft.clone()),
+            mailer: Mailer::new(&settings.email),
+            http_client: reqwest::Client::new(),
+            sequences: group_into_sequences(rules),
+            settings,
+            mock,
+        }
+    }
+
+    /// Builds an engine from `settings.json` and `reminders.json`.
+    pub fn load(mock: bool) -> Result<Self> {
+        let settings = config::load_settings().context("Failed to load settings.json")?;
+        let rules = config::load_reminders().context("Failed to load reminders.json.")?;
+        Ok(Self::new(settings, rules, mock))
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn client(&self) -> &WacraftClient {
+        &self.client
+    }
+
+    /// Every rule, in sequence order.
+    fn rules(&self) -> impl Iterator<Item = &ReminderRule> {
+        self.sequences.iter().flat_map(|(_, steps)| steps)
+    }
+
+    /// Delivers a rendered action once, through its channel.
+    pub async fn deliver(&self, action: &RenderedAction) -> Result<()> {
+        action
+            .deliver(&self.client, &self.mailer, &self.http_client)
+            .await
+    }
+
+    /// Evaluates the rules for a single contact and runs the due reminder steps.
+    /// `conversation` is the contact's latest message, when the caller already has it.
+    /// `delivery_ledger` is the ledger as loaded when the run started; what happens to
+    /// the contact is recorded on disk.
+    pub async fn process_contact(
+        &self,
+        contact_id: &str,
+        conversation: Option<&Conversation>,
+        delivery_ledger: &Ledger,
+        selection: &RuleSelection,
+    ) -> Result<()> {
+        let mut activity = ContactActivity::default();
+        if let Some(conv) = conversation {
+            activity.observe(conv, contact_id);
+        }
+        let mut history_scanned = false;
+        self.evaluate(
+            contact_id,
+            &mut activity,
+            &mut history_scanned,
+            conversation,
+            delivery_ledger,
+            selection,
+        )
+        .await
+    }
+
+    /// Evaluates the rules for a contact of the activity index. When the contact's
+    /// history has to be scanned, the entry is updated so the next run can skip it.
+    pub async fn process_indexed_contact(
+        &self,
+        contact_id: &str,
+        entry: &mut IndexedContact,
+        delivery_ledger: &Ledger,
+        selection: &RuleSelection,
+    ) -> Result<()> {
+        self.evaluate(
+            contact_id,
+            &mut entry.activity,
+            &mut entry.history_scanned,
+            Some(&entry.last_message),
+            delivery_ledger,
+            selection,
+        )
+        .await
+    }
+
+    /// Reports what the engine would do next for a contact in each reminder sequence,
+    /// without sending or recording anything. Rules with their own schedule are
+    /// reported as waiting for it, evaluated in `timezone`.
+    pub async fn plan_contact(
+        &self,
+        contact_id: &str,
+        conversation: Option<&Conversation>,
+        delivery_ledger: &Ledger,
+        timezone: Tz,
+    ) -> Result<Vec<PlanEntry>> {
+        let mut activity = ContactActivity::default();
+        if let Some(conv) = conversation {
+            activity.observe(conv, contact_id);
+        }
+        let mut history_scanned = false;
+        let contact = self
+            .resolve_contact(
+                contact_id,
+                &mut activity,
+                &mut history_scanned,
+                conversation,
+            )
+            .await?;
+        let contact = contact.as_ref();
+        let wrp_contact = contact.contact.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Messaging product {} is missing contact details",
+                contact_id
+            )
+        })?;
+        let episode_start = activity.episode_start();
+        let now = Utc::now();
+        // Like the daemon's own checks: rules with a schedule wait for it.
+        let selection = RuleSelection::Scheduled {
+            default: true,
+            rules: HashSet::new(),
+        };
+
+        let mut entries = Vec::new();
+        for (sequence, steps) in &self.sequences {
+            let plan = plan_sequence(steps, contact, &activity, delivery_ledger, &selection, now);
+            let step = plan.next.step();
+            let since = match step {
+                Some(step) => activity.since(step.inactivity_basis),
+                None => activity.last_inbound_at,
+            };
+            let mut entry = PlanEntry {
+                contact_id: contact_id.to_string(),
+                contact_name: Some(wrp_contact.name.clone()),
+                sequence: Some(sequence.clone()),
+                inactive_since: since,
+                inactive_hours: since.map(|at| now.signed_duration_since(at).num_hours()),
+                status: plan.next.status(),
+                rule: step.map(|step| step.name.clone()),
+                due_at: plan.next.due_at(now, timezone),
+                actions: Vec::new(),
+                next_rule: None,
+                next_due_at: None,
+                error: None,
+            };
+
+            if let Some(step) = step {
+                let ctx = TemplateContext::new(
+                    contact,
+                    wrp_contact,
+                    conversation,
+                    step,
+                    since,
+                    delivery_ledger.delivered_rules(contact_id, episode_start),
+                    now,
+                );
+                entry.actions = step
+                    .all_actions()
+                    .map(
+                        |action| match self.render_action(action, &ctx, contact_id, &activity) {
+                            Ok(rendered) => PlannedAction {
+                                kind: action.kind(),
+                                target: Some(rendered.target()),
+                                error: None,
+                            },
+                            Err(e) => PlannedAction {
+                                kind: action.kind(),
+                                target: None,
+                                error: Some(format!("{:#}", e)),
+                            },
+                        },
+                    )
+                    .collect();
+            }
+            // For a rule about to run, look one step ahead.
+            if let NextStep::Due { step, .. }
+            | NextStep::Deferred { step, .. }
+            | NextStep::Scheduled { step } = plan.next
+            {
+                let position = steps.iter().position(|s| std::ptr::eq(s, step));
+                let rest = position.map_or(&[][..], |position| &steps[position + 1..]);
+                let following =
+                    plan_sequence(rest, contact, &activity, delivery_ledger, &selection, now).next;
+                entry.next_rule = following.step().map(|step| step.name.clone());
+                entry.next_due_at = following.due_at(now, timezone);
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    async fn evaluate(
+        &self,
+        contact_id: &str,
+        activity: &mut ContactActivity,
+        history_scanned: &mut bool,
+        conversation: Option<&Conversation>,
+        delivery_ledger: &Ledger,
+        selection: &RuleSelection,
+    ) -> Result<()> {
+        info!("Preparing to send reminder to contact: {}", contact_id);
+
+        if self.sequences.is_empty() {
+            info!(
+                "No reminder rules found. Nothing to do for contact {}.",
+                contact_id
+            );
+            return Ok(());
+        }
+
+        // 1. Work out when the contact was last active in each direction
+        let contact = self
+            .resolve_contact(contact_id, activity, history_scanned, conversation)
+            .await?;
+        let contact = contact.as_ref();
+
+        // 2. Determine which step of each reminder sequence is due
+        let episode_start = activity.episode_start();
+        let now = Utc::now();
+
+        let wrp_contact = contact.contact.as_ref().ok_or_else(|| {
+            anyhow!(
+                "Messaging product {} is missing contact details",
+                contact_id
+            )
+        })?;
+
+        // New activity starts a new episode, so steps deferred in the old one are moot.
+        if !self.mock && delivery_ledger.has_stale_deferrals(contact_id, episode_start) {
+            ledger::prune_deferrals(contact_id, episode_start)
+                .await
+                .context("Failed to prune stale deferred steps from the ledger.")?;
+        }
+        let mut applied_any = false;
+        let mut failures: Vec<String> = Vec::new();
+
+        for (sequence, steps) in &self.sequences {
+            let plan = plan_sequence(steps, contact, activity, delivery_ledger, selection, now);
+            for (step, missing) in &plan.skipped {
+                println!(
+                    "Skipping step '{}' of sequence '{}' for {}: required steps not delivered: {}.",
+                    step.name,
+                    sequence,
+                    contact_id,
+                    missing.join(", ")
+                );
+                if !self.mock {
+                    ledger::record(
+                        contact_id,
+                        &step.name,
+                        episode_start,
+                        DeliveryStatus::Skipped,
+                    )
+                    .await
+                    .context("Failed to record skipped step in the ledger.")?;
+                }
+            }
+
+            let (step, since) = match plan.next {
+                NextStep::Due { step, since } => (step, since),
+                // Due steps outside their sending window wait for the next allowed slot,
+                // and hold back the steps after them.
+                NextStep::Deferred {
+                    step,
+                    window,
+                    until: Some(until),
+                } => {
+                    println!(
+                        "⏸  Step '{}' of sequence '{}' for {} is deferred until {} ({}).",
+                        step.name,
+                        sequence,
+                        contact_id,
+                        until.with_timezone(&window.timezone),
+                        window.timezone
+                    );
+                    let already_recorded = delivery_ledger
+                        .find(contact_id, &step.name, episode_start)
+                        .is_some_and(|record| record.deferred_until == Some(until));
+                    if !self.mock && !already_recorded {
+                        ledger::record_deferral(contact_id, &step.name, episode_start, until)
+                            .await
+                            .context("Failed to record deferred step in the ledger.")?;
+                    }
+                    continue;
+                }
+                NextStep::Deferred {
+                    step, until: None, ..
+                } => {
+                    warn!(
+                        "Sending window of step '{}' never opens. It will not be sent.",
+                        step.name
+                    );
+                    continue;
+                }
+                NextStep::NoActivity { step } => {
+                    info!(
+                        "Contact {} has no {:?} messages to measure step '{}' from.",
+                        contact_id, step.inactivity_basis, step.name
+                    );
+                    continue;
+                }
+                NextStep::Waiting { .. } | NextStep::Scheduled { .. } | NextStep::Done => {
+                    continue;
+                }
+            };
+            let inactive_duration = now.signed_duration_since(since);
+            let delivered_steps = delivery_ledger.delivered_rules(contact_id, episode_start);
+
+            println!(
+                "Contact has been inactive for {} hours. Applying step '{}' of sequence '{}'.",
+                inactive_duration.num_hours(),
+                step.name,
+                sequence
+            );
+            if !delivered_steps.is_empty() {
+                info!(
+                    "Steps already delivered to {} in this episode: {}",
+                    contact_id,
+                    delivered_steps.join(", ")
+                );
+            }
+
+            // 3. Execute the actions defined in the step
+            let ctx = TemplateContext::new(
+                contact,
+                wrp_contact,
+                conversation,
+                step,
+                Some(since),
+                delivered_steps,
+                now,
+            );
+            let mut outcomes: Vec<(&Action, Result<()>)> = Vec::new();
+            // Actions that were rendered but could not be delivered, for the dead letters.
+            let mut undelivered: Vec<(usize, RenderedAction, String)> = Vec::new();
+            for (index, action) in step.all_actions().enumerate() {
+                let result = match self.render_action(action, &ctx, contact_id, activity) {
+                    Ok(rendered) => {
+                        let result = self.deliver_action(&rendered, &ctx).await;
+                        if let Err(e) = &result {
+                            undelivered.push((index, rendered, format!("{:#}", e)));
+                        }
+                        result
+                    }
+                    Err(e) => Err(e),
+                };
+                let succeeded = result.is_ok();
+                outcomes.push((action, result));
+                if succeeded && step.action_mode == ActionMode::FirstSuccess {
+                    break;
+                }
+            }
+            if outcomes.is_empty() {
+                println!("✅ No action for {}.", contact_id);
+            }
+            report_outcomes(contact_id, &step.name, &outcomes);
+
+            let failed: Vec<String> = outcomes
+                .iter()
+                .filter_map(|(action, result)| {
+                    result
+                        .as_ref()
+                        .err()
+                        .map(|e| format!("{}: {:#}", action.kind(), e))
+                })
+                .collect();
+            // With "first_success" mode, earlier failures don't matter once one succeeds.
+            let any_succeeded = outcomes.is_empty() || outcomes.iter().any(|(_, r)| r.is_ok());
+            let step_failed = match step.action_mode {
+                ActionMode::All => !failed.is_empty(),
+                ActionMode::FirstSuccess => !any_succeeded,
+            };
+            if step_failed {
+                failures.push(format!(
+                    "step '{}' of sequence '{}': {}",
+                    step.name,
+                    sequence,
+                    failed.join("; ")
+                ));
+            }
+            if !self.mock {
+                store_dead_letters(
+                    contact_id,
+                    step,
+                    episode_start,
+                    &outcomes,
+                    undelivered,
+                    any_succeeded,
+                )
+                .await?;
+            }
+            // Steps where nothing succeeded are retried on the next evaluation.
+            // Once an action went out, the step is recorded so it is not sent twice.
+            if !any_succeeded {
+                continue;
+            }
+
+            if !self.mock {
+                ledger::record(
+                    contact_id,
+                    &step.name,
+                    episode_start,
+                    DeliveryStatus::Delivered,
+                )
+                .await
+                .context("Failed to record delivery in the ledger.")?;
+            }
+            applied_any = true;
+        }
+
+        if !applied_any && failures.is_empty() {
+            info!("Contact {} has no reminder step due right now.", contact_id);
+        }
+
+        if !failures.is_empty() {
+            anyhow::bail!(
+                "Failed to deliver reminders to contact {}: {}",
+                contact_id,
+                failures.join(" | ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Works out when the contact was last active in each direction and looks up
+    /// the messaging product contact.
+    ///
+    /// The caller hands over what it already knows, usually from the latest message;
+    /// the contact's history is only scanned when that doesn't settle what the rules
+    /// need, and at most once until a new message arrives.
+    async fn resolve_contact<'c>(
+        &self,
+        contact_id: &str,
+        activity: &mut ContactActivity,
+        history_scanned: &mut bool,
+        conversation: Option<&'c Conversation>,
+    ) -> Result<Cow<'c, MessagingProductContact>> {
+        let needs_history = !*history_scanned
+            && (activity.last_inbound_at.is_none()
+                || self
+                    .rules()
+                    .any(|rule| activity.since(rule.inactivity_basis).is_none()));
+        if needs_history {
+            *activity = self.client.get_contact_activity(contact_id).await?;
+            if let Some(conv) = conversation {
+                activity.observe(conv, contact_id);
+            }
+            *history_scanned = true;
+        }
+        if activity.last_message_at().is_none() {
+            anyhow::bail!("No conversation found for contact {}", contact_id);
+        }
+
+        // Use the contact embedded in the message when the API included it.
+        Ok(
+            match conversation.and_then(|conv| conv.contact(contact_id)) {
+                Some(ctt) => Cow::Borrowed(ctt),
+                None => Cow::Owned(
+                    self.client
+                        .get_messaging_product_contact_by_id(contact_id)
+                        .await?
+                        .ok_or_else(|| anyhow!("No messaging product contact found"))?,
+                ),
+            },
+        )
+    }
+
+    /// Renders a single reminder action for a contact.
+    fn render_action(
+        &self,
+        action: &Action,
+        ctx: &TemplateContext<'_>,
+        contact_id: &str,
+        activity: &ContactActivity,
+    ) -> Result<RenderedAction> {
+        Ok(match action {
+            Action::WacraftMessage(action) => {
+                let product_details = ctx
+                    .phone_number
+                    .ok_or_else(|| anyhow!("Contact {} missing product details", contact_id))?;
+
+                // WhatsApp rejects free-form messages once the customer-service window
+                // has closed, so switch to the fallback template instead of failing.
+                let payload_base: MessagePayloadBase = if action.sender_data.is_free_form()
+                    && !activity.in_customer_service_window(Utc::now())
+                {
+                    let template = action.fallback_template.clone().ok_or_else(|| {
+                        anyhow!(
+                            "Contact {} is outside the 24-hour customer-service window and rule '{}' has no 'fallback_template'",
+                            contact_id,
+                            ctx.rule.name
+                        )
+                    })?;
+                    info!(
+                        "Contact {} is outside the 24-hour window. Using fallback template '{}'.",
+                        contact_id, template.name
+                    );
+                    action.sender_data.with_template(template)
+                } else {
+                    action.sender_data.clone()
+                };
+                let payload = MessagePayload {
+                    base: payload_base.render(ctx)?,
+                    to: product_details.wa_id.clone(),
+                };
+
+                RenderedAction::WacraftMessage(Box::new(SendWhatsAppMessage {
+                    to_id: contact_id.to_string(),
+                    sender_data: payload,
+                }))
+            }
+            Action::Email(action) => RenderedAction::Email(email::render_reminder_email(
+                &self.settings.email,
+                ctx,
+                action,
+            )?),
+            Action::HttpRequest(action) => {
+                RenderedAction::HttpRequest(http_request::render_http_request(action, ctx)?)
+            }
+        })
+    }
+
+    /// Delivers a rendered action, retrying transient failures per the action type's policy.
+    async fn deliver_action(
+        &self,
+        rendered: &RenderedAction,
+        ctx: &TemplateContext<'_>,
+    ) -> Result<()> {
+        let contact_id = &ctx.messaging_product_contact.id;
+        if self.mock {
+            println!(
+                "🧪 Mock run: {} to {} for {} was not delivered.",
+                rendered.kind(),
+                rendered.target(),
+                contact_id
+            );
+            return Ok(());
+        }
+        match rendered {
+            RenderedAction::WacraftMessage(_) => {
+                println!("Sending Wacraft message to {}...", ctx.contact.name)
+            }
+            RenderedAction::Email(_) => {
+                println!("Sending email reminder to {}...", ctx.contact.name)
+            }
+            RenderedAction::HttpRequest(_) => {
+                println!("Executing HTTP request for rule '{}'...", ctx.rule.name)
+            }
+        }
+        let what = format!("Action '{}' of step '{}'", rendered.kind(), ctx.rule.name);
+        retry::retry(self.settings.retry.for_action(rendered), &what, || {
+            self.deliver(rendered)
+        })
+        .await?;
+        match rendered {
+            RenderedAction::WacraftMessage(_) => {
+                println!("✅ Successfully sent Wacraft reminder to {}.", contact_id)
+            }
+            RenderedAction::Email(_) => {
+                println!("✅ Successfully sent email reminder to {}.", contact_id)
+            }
+            RenderedAction::HttpRequest(_) => {
+                println!("✅ Successfully executed HTTP request for {}.", contact_id)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints and logs the outcome of every action that ran for a step.
+fn report_outcomes(contact_id: &str, rule_name: &str, outcomes: &[(&Action, Result<()>)]) {
+    let total = outcomes.len();
+    for (index, (action, result)) in outcomes.iter().enumerate() {
+        match result {
+            Ok(()) => {
+                info!(
+                    "Action {}/{} ({}) of rule '{}' succeeded for contact {}.",
+                    index + 1,
+                    total,
+                    action.kind(),
+                    rule_name,
+                    contact_id
+                );
+            }
+            Err(e) => {
+                println!(
+                    "❌ Action {}/{} ({}) failed for {}: {:#}",
+                    index + 1,
+                    total,
+                    action.kind(),
+                    contact_id,
+                    e
+                );
+                warn!(
+                    "Action {}/{} ({}) of rule '{}' failed for contact {}: {:?}",
+                    index + 1,
+                    total,
+                    action.kind(),
+                    rule_name,
+                    contact_id,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Groups rules into their sequences, keeping the order in which sequences first
+/// appear in `reminders.json`. Steps within a sequence are ordered by
+/// `inactive_for_hours`, with ties kept in file order.
+fn group_into_sequences(rules: Vec<ReminderRule>) -> Vec<(String, Vec<ReminderRule>)> {
+    let mut sequences: Vec<(String, Vec<ReminderRule>)> = Vec::new();
+    for rule in rules {
+        let name = rule.sequence_name().to_string();
+        match sequences.iter_mut().find(|(seq, _)| *seq == name) {
+            Some((_, steps)) => steps.push(rule),
+            None => sequences.push((name, vec![rule])),
+        }
+    }
+    for (_, steps) in &mut sequences {
+        steps.sort_by_key(|step| step.inactive_for_hours);
+    }
+    sequences
+}
+
+/// Works out which step of a sequence runs or becomes due next for a contact.
+/// Nothing is recorded, so dry runs can use it too.
+fn plan_sequence<'r>(
+    steps: &'r [ReminderRule],
+    contact: &MessagingProductContact,
+    activity: &ContactActivity,
+    delivery_ledger: &Ledger,
+    selection: &RuleSelection,
+    now: DateTime<Utc>,
+) -> SequencePlan<'r> {
+    let contact_id = contact.id.as_str();
+    let episode_start = activity.episode_start();
+    let mut skipped = Vec::new();
+
+    for step in steps {
+        // Each step fires at most once per inactivity episode.
+        let previous = delivery_ledger.find(contact_id, &step.name, episode_start);
+        if previous.is_some_and(|record| record.status.is_final()) {
+            continue;
+        }
+        // Steps aimed at a different audience don't apply to this contact at all.
+        if let Some(audience) = &step.audience
+            && !audience.matches(contact, now)
+        {
+            debug!(
+                "Contact {} is not in the audience of step '{}'.",
+                contact_id, step.name
+            );
+            continue;
+        }
+        // Steps are ordered, so a step that is not due yet blocks the ones after it.
+        let next = match activity.since(step.inactivity_basis) {
+            None => NextStep::NoActivity { step },
+            Some(since) => {
+                let due_at = since + Duration::hours(step.inactive_for_hours as i64);
+                if now < due_at {
+                    NextStep::Waiting { step, due_at }
+                } else if !selection.includes(step) {
+                    // A due step with its own schedule waits for that schedule to fire.
+                    NextStep::Scheduled { step }
+                } else {
+                    let delivered_steps =
+                        delivery_ledger.delivered_rules(contact_id, episode_start);
+                    let missing: Vec<&str> = step
+                        .requires
+                        .iter()
+                        .map(String::as_str)
+                        .filter(|required| !delivered_steps.contains(required))
+                        .collect();
+                    if !missing.is_empty() {
+                        skipped.push((step, missing));
+                        continue;
+                    }
+                    // Due steps outside their sending window wait for the next
+                    // allowed slot, and hold back the steps after them.
+                    match &step.sending_window {
+                        Some(window) if !window.is_open(now) => NextStep::Deferred {
+                            step,
+                            window,
+                            until: window.next_open(now),
+                        },
+                        _ => NextStep::Due { step, since },
+                    }
+                }
+            }
+        };
+        return SequencePlan { skipped, next };
+    }
+    SequencePlan {
+        skipped,
+        next: NextStep::Done,
+    }
+}
+
+/// Stores the actions of a step that failed after all retries as dead letters, and
+/// drops the stored failures of actions that went through this time.
+///
+/// In "first_success" mode, failures don't matter once any action succeeded.
+async fn store_dead_letters(
+    contact_id: &str,
+    step: &ReminderRule,
+    episode_start: DateTime<Utc>,
+    outcomes: &[(&Action, Result<()>)],
+    mut undelivered: Vec<(usize, RenderedAction, String)>,
+    any_succeeded: bool,
+) -> Result<()> {
+    let resolved: Vec<usize> = if step.action_mode == ActionMode::FirstSuccess && any_succeeded {
+        undelivered.clear();
+        (0..step.all_actions().count()).collect()
+    } else {
+        outcomes
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, result))| result.is_ok())
+            .map(|(index, _)| index)
+            .collect()
+    };
+    dead_letters::resolve(contact_id, &step.name, episode_start, &resolved)
+        .await
+        .context("Failed to update the failed deliveries.")?;
+
+    let first_success = step.action_mode == ActionMode::FirstSuccess;
+    for (index, payload, error) in undelivered {
+        dead_letters::add(
+            contact_id,
+            &step.name,
+            episode_start,
+            index,
+            payload,
+            first_success,
+            error,
+        )
+        .await
+        .context("Failed to store a failed delivery.")?;
+        println!(
+            "Stored the failed delivery. Run 'reminders failed list' to inspect it and 'reminders retry' to replay it."
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{at_hour, contact};
+
+    /// The steps of one sequence, as `group_into_sequences` orders them.
+    fn sequence(json: serde_json::Value) -> Vec<ReminderRule> {
+        let mut sequences = group_into_sequences(serde_json::from_value(json).unwrap());
+        assert_eq!(sequences.len(), 1);
+        sequences.remove(0).1
+    }
+
+    fn rule(name: &str, inactive_for_hours: u64) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "inactive_for_hours": inactive_for_hours,
+            "action": {"type": "http_request", "method": "post", "url": "http://localhost/"},
+        })
+    }
+
+    /// Contact activity with the last inbound message at `hour`.
+    fn inbound_at(hour: i64) -> ContactActivity {
+        ContactActivity {
+            last_inbound_at: Some(at_hour(hour)),
+            last_outbound_at: None,
+        }
+    }
+
+    /// A ledger in which the given steps were delivered in the episode starting at `hour`.
+    fn delivered(hour: i64, rule_names: &[&str]) -> Ledger {
+        let records: Vec<_> = rule_names
+            .iter()
+            .map(|name| {
+                serde_json::json!({
+                    "rule_name": name,
+                    "activity_at": at_hour(hour),
+                    "recorded_at": at_hour(hour),
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({"contacts": {"mp1": records}})).unwrap()
+    }
+
+    fn next_name(plan: &SequencePlan) -> Option<String> {
+        plan.next.step().map(|step| step.name.clone())
+    }
+
+    #[test]
+    fn steps_are_ordered_by_inactivity() {
+        let steps = sequence(serde_json::json!([rule("late", 48), rule("early", 2)]));
+        let names: Vec<&str> = steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(names, ["early", "late"]);
+    }
+
+    #[test]
+    fn plans_the_first_undelivered_step() {
+        let steps = sequence(serde_json::json!([rule("early", 2), rule("late", 48)]));
+        let activity = inbound_at(0);
+
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &activity,
+            &Ledger::default(),
+            &RuleSelection::All,
+            at_hour(1),
+        );
+        assert!(matches!(plan.next, NextStep::Waiting { due_at, .. } if due_at == at_hour(2)));
+
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &activity,
+            &Ledger::default(),
+            &RuleSelection::All,
+            at_hour(3),
+        );
+        assert!(matches!(plan.next, NextStep::Due { since, .. } if since == at_hour(0)));
+        assert_eq!(next_name(&plan).as_deref(), Some("early"));
+
+        let ledger = delivered(0, &["early"]);
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &activity,
+            &ledger,
+            &RuleSelection::All,
+            at_hour(3),
+        );
+        assert!(matches!(plan.next, NextStep::Waiting { due_at, .. } if due_at == at_hour(48)));
+        assert_eq!(next_name(&plan).as_deref(), Some("late"));
+
+        let ledger = delivered(0, &["early", "late"]);
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &activity,
+            &ledger,
+            &RuleSelection::All,
+            at_hour(50),
+        );
+        assert!(matches!(plan.next, NextStep::Done));
+    }
+
+    #[test]
+    fn new_activity_restarts_the_sequence() {
+        let steps = sequence(serde_json::json!([rule("early", 2), rule("late", 48)]));
+        let ledger = delivered(0, &["early"]);
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &inbound_at(10),
+            &ledger,
+            &RuleSelection::All,
+            at_hour(13),
+        );
+        assert_eq!(next_name(&plan).as_deref(), Some("early"));
+    }
+
+    #[test]
+    fn skips_steps_whose_requirements_were_not_delivered() {
+        let mut guarded = rule("guarded", 2);
+        guarded["requires"] = serde_json::json!(["welcome"]);
+        let steps = sequence(serde_json::json!([guarded, rule("fallback", 4)]));
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &inbound_at(0),
+            &Ledger::default(),
+            &RuleSelection::All,
+            at_hour(5),
+        );
+        assert_eq!(plan.skipped.len(), 1);
+        assert_eq!(plan.skipped[0].0.name, "guarded");
+        assert_eq!(plan.skipped[0].1, ["welcome"]);
+        assert_eq!(next_name(&plan).as_deref(), Some("fallback"));
+    }
+
+    #[test]
+    fn steps_without_matching_activity_wait_for_it() {
+        let mut outbound = rule("outbound", 2);
+        outbound["inactivity_basis"] = serde_json::json!("outbound");
+        let steps = sequence(serde_json::json!([outbound]));
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &inbound_at(0),
+            &Ledger::default(),
+            &RuleSelection::All,
+            at_hour(3),
+        );
+        assert!(matches!(plan.next, NextStep::NoActivity { .. }));
+    }
+
+    #[test]
+    fn due_steps_wait_for_their_sending_window() {
+        let mut windowed = rule("windowed", 2);
+        windowed["sending_window"] =
+            serde_json::json!({"timezone": "UTC", "hours": [{"start": 9, "end": 17}]});
+        let steps = sequence(serde_json::json!([windowed]));
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &inbound_at(0),
+            &Ledger::default(),
+            &RuleSelection::All,
+            at_hour(3),
+        );
+        assert!(
+            matches!(plan.next, NextStep::Deferred { until: Some(until), .. } if until == at_hour(9))
+        );
+    }
+
+    #[test]
+    fn due_steps_wait_for_their_schedule() {
+        let mut scheduled = rule("scheduled", 2);
+        scheduled["schedule"] = serde_json::json!("0 9 * * *");
+        let steps = sequence(serde_json::json!([scheduled]));
+        let selection = RuleSelection::Scheduled {
+            default: true,
+            rules: HashSet::new(),
+        };
+        let plan = plan_sequence(
+            &steps,
+            &contact(None, at_hour(0)),
+            &inbound_at(0),
+            &Ledger::default(),
+            &selection,
+            at_hour(3),
+        );
+        assert!(matches!(plan.next, NextStep::Scheduled { .. }));
+    }
+}
@@ -1,66 +1,82 @@
 use crate::config::models::HttpRequestAction;
-use crate::core::wacraft::models::Contact;
+use crate::core::retry::HttpStatusError;
+use crate::core::template::{Escape, TemplateContext};
 use anyhow::{Context, Result, anyhow};
 use reqwest::{Client, Method};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 
-/// Sends a generic HTTP request based on a configured action.
-/// It replaces placeholders in the URL and body with contact data.
-pub async fn send_http_request(action: &HttpRequestAction, contact: &Contact) -> Result<()> {
-    let client = Client::new();
+/// An HTTP request action rendered for a contact, ready to be sent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderedHttpRequest {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
 
-    // 1. Replace placeholders in the URL.
-    let url = replace_placeholders(&action.url, contact);
+/// Renders the URL, headers and body of a configured action as templates with the
+/// reminder's context.
+pub fn render_http_request(
+    action: &HttpRequestAction,
+    ctx: &TemplateContext<'_>,
+) -> Result<RenderedHttpRequest> {
+    // 1. Validate the HTTP method up front, so a typo is not retried or stored.
+    parse_method(&action.method)?;
 
-    // 2. Determine the HTTP method.
-    let method = Method::from_bytes(action.method.to_uppercase().as_bytes())
-        .map_err(|_| anyhow!("Invalid HTTP method: '{}'", action.method))?;
+    // 2. Render the URL. Inserted values are URL-encoded.
+    let url = ctx.render(&action.url, Escape::Url)?;
 
-    // 3. Build the request.
-    let mut request_builder = client.request(method, url);
+    // 3. Render the header values.
+    let headers = action
+        .headers
+        .iter()
+        .map(|(key, value)| Ok((key.clone(), ctx.render(value, Escape::None)?)))
+        .collect::<Result<_>>()?;
 
-    // 4. Add headers, replacing placeholders.
-    for (key, value) in &action.headers {
-        request_builder = request_builder.header(key, replace_placeholders(value, contact));
-    }
+    // 4. Render the JSON body if present, rendering each of its strings.
+    let body = match &action.body {
+        Value::Null => None,
+        body => Some(ctx.render_json(body)?),
+    };
+
+    Ok(RenderedHttpRequest {
+        method: action.method.clone(),
+        url,
+        headers,
+        body,
+    })
+}
 
-    // 5. Add JSON body if present, replacing placeholders.
-    if action.body != Value::Null {
-        let body_str = action.body.to_string();
-        let processed_body_str = replace_placeholders(&body_str, contact);
-        let processed_body_json: Value = serde_json::from_str(&processed_body_str)
-            .context("Failed to parse JSON body after placeholder replacement")?;
-        request_builder = request_builder.json(&processed_body_json);
+/// Sends a rendered HTTP request.
+pub async fn send_http_request(client: &Client, request: &RenderedHttpRequest) -> Result<()> {
+    // 1. Build the request.
+    let mut request_builder = client.request(parse_method(&request.method)?, &request.url);
+    for (key, value) in &request.headers {
+        request_builder = request_builder.header(key, value);
+    }
+    if let Some(body) = &request.body {
+        request_builder = request_builder.json(body);
     }
 
-    // 6. Send the request.
+    // 2. Send the request.
     let response = request_builder
         .send()
         .await
         .context("Failed to send HTTP request")?;
 
-    // 7. Check the response status.
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Could not read response body".to_string());
-        anyhow::bail!(
-            "HTTP request failed with status {}. Response: {}",
-            status,
-            body
-        );
-    }
+    // 3. Check the response status.
+    HttpStatusError::check(response)
+        .await
+        .context("HTTP request failed")?;
 
     Ok(())
 }
 
-/// A simple placeholder replacement function.
-fn replace_placeholders(template: &str, contact: &Contact) -> String {
-    let email = contact.email.as_deref().unwrap_or("");
-    template
-        .replace("{contact_id}", &contact.id)
-        .replace("{contact_name}", &contact.name)
-        .replace("{contact_email}", email)
+fn parse_method(method: &str) -> Result<Method> {
+    Method::from_bytes(method.to_uppercase().as_bytes())
+        .map_err(|_| anyhow!("Invalid HTTP method: '{}'", method))
 }
@@ -1,3 +1,8 @@
+pub mod action;
 pub mod email;
+pub mod engine;
 pub mod http_request;
+pub mod retry;
+pub mod template;
+pub mod throttle;
 pub mod wacraft;
@@ -0,0 +1,333 @@
+use crate::config::models::RetryPolicy;
+use anyhow::{Error, Result};
+use log::warn;
+use reqwest::{Response, StatusCode, header::RETRY_AFTER};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+/// Whether a failed operation may succeed if it is attempted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// E.g. a connection error, a timeout, HTTP 429 or 5xx, or an SMTP 4xx reply.
+    /// The server may have said how long to wait before retrying.
+    Transient { retry_after: Option<Duration> },
+    /// Anything else, e.g. HTTP 4xx, invalid addresses or unreadable templates.
+    Permanent,
+}
+
+/// An HTTP response with an unsuccessful status.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    /// The parsed `Retry-After` header, if the server sent one.
+    pub retry_after: Option<Duration>,
+    pub body: String,
+}
+
+impl HttpStatusError {
+    /// Returns the response unchanged if it was successful, or its status, `Retry-After`
+    /// header and body as an error.
+    pub async fn check(response: Response) -> Result<Response, HttpStatusError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Could not read response body".to_string());
+        Err(HttpStatusError {
+            status,
+            retry_after,
+            body,
+        })
+    }
+
+    fn class(&self) -> ErrorClass {
+        if self.status == StatusCode::REQUEST_TIMEOUT
+            || self.status == StatusCode::TOO_MANY_REQUESTS
+            || self.status.is_server_error()
+        {
+            ErrorClass::Transient {
+                retry_after: self.retry_after,
+            }
+        } else {
+            ErrorClass::Permanent
+        }
+    }
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Status: {}, Body: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Parses a `Retry-After` value, which is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    Some(
+        at.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Classifies an error by looking through its chain of causes for a known error type.
+/// Unknown errors are permanent.
+pub fn classify(err: &Error) -> ErrorClass {
+    let transient = ErrorClass::Transient { retry_after: None };
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return e.class();
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if e.is_connect() || e.is_timeout() {
+                return transient;
+            }
+            if let Some(status) = e.status() {
+                return HttpStatusError {
+                    status,
+                    retry_after: None,
+                    body: String::new(),
+                }
+                .class();
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<lettre::transport::smtp::Error>() {
+            if e.is_transient() || e.is_timeout() {
+                return transient;
+            }
+            if e.is_permanent() {
+                return ErrorClass::Permanent;
+            }
+            // Connection errors carry the underlying I/O error as their source.
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::UnexpectedEof => transient,
+                _ => ErrorClass::Permanent,
+            };
+        }
+    }
+    ErrorClass::Permanent
+}
+
+/// Runs `operation` until it succeeds, fails permanently or runs out of attempts,
+/// waiting between attempts as the policy says. A `Retry-After` from the server takes
+/// precedence over the computed backoff, even beyond `max_backoff_ms`, unless it is
+/// longer than `max_retry_after_ms`, in which case we give up instead of stalling.
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, what: &str, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let err = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+        let ErrorClass::Transient { retry_after } = classify(&err) else {
+            return Err(err);
+        };
+        if attempt >= max_attempts {
+            return Err(err.context(format!("{} failed after {} attempts", what, attempt)));
+        }
+        let delay = match retry_after {
+            Some(wait) if wait > policy.max_retry_after() => {
+                return Err(err.context(format!(
+                    "{} asked to retry after {}s, more than 'max_retry_after_ms' allows",
+                    what,
+                    wait.as_secs()
+                )));
+            }
+            Some(wait) => wait,
+            None => policy.backoff(attempt),
+        };
+        warn!(
+            "{} failed (attempt {}/{}): {:#}. Retrying in {:.1}s.",
+            what,
+            attempt,
+            max_attempts,
+            err,
+            delay.as_secs_f64()
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+    use std::cell::Cell;
+
+    fn status_error(status: StatusCode, retry_after: Option<Duration>) -> Error {
+        Error::new(HttpStatusError {
+            status,
+            retry_after,
+            body: String::new(),
+        })
+    }
+
+    /// A policy that retries without waiting and without jitter.
+    fn instant_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff_ms: 0,
+            jitter: 0.0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn parse_retry_after_reads_seconds_and_dates() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 5 "), Some(Duration::from_secs(5)));
+        // A date in the past means "now".
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let in_a_minute = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = parse_retry_after(&in_a_minute).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn classify_http_statuses() {
+        let retry_after = Some(Duration::from_secs(30));
+        assert_eq!(
+            classify(&status_error(StatusCode::TOO_MANY_REQUESTS, retry_after)),
+            ErrorClass::Transient { retry_after }
+        );
+        for status in [
+            StatusCode::REQUEST_TIMEOUT,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ] {
+            assert_eq!(
+                classify(&status_error(status, None)),
+                ErrorClass::Transient { retry_after: None }
+            );
+        }
+        for status in [StatusCode::BAD_REQUEST, StatusCode::NOT_FOUND] {
+            assert_eq!(classify(&status_error(status, None)), ErrorClass::Permanent);
+        }
+    }
+
+    #[test]
+    fn classify_looks_through_context() {
+        let err = status_error(StatusCode::BAD_GATEWAY, None).context("Failed to send message");
+        assert_eq!(classify(&err), ErrorClass::Transient { retry_after: None });
+
+        let refused: Result<()> = Err(io::Error::from(io::ErrorKind::ConnectionRefused).into());
+        let err = refused.context("Failed to connect").unwrap_err();
+        assert_eq!(classify(&err), ErrorClass::Transient { retry_after: None });
+
+        let missing = Error::new(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(classify(&missing), ErrorClass::Permanent);
+        assert_eq!(
+            classify(&anyhow!("Template not found")),
+            ErrorClass::Permanent
+        );
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            max_backoff_ms: 3000,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(2), Duration::from_millis(2000));
+        assert_eq!(policy.backoff(3), Duration::from_millis(3000));
+        assert_eq!(policy.backoff(40), Duration::from_millis(3000));
+    }
+
+    #[tokio::test]
+    async fn retry_stops_at_max_attempts() {
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&instant_policy(3), "Test", || {
+            calls.set(calls.get() + 1);
+            async { Err(status_error(StatusCode::SERVICE_UNAVAILABLE, None)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_returns_the_first_success() {
+        let calls = Cell::new(0);
+        let result = retry(&instant_policy(3), "Test", || {
+            calls.set(calls.get() + 1);
+            let attempt = calls.get();
+            async move {
+                match attempt {
+                    1 => Err(status_error(StatusCode::BAD_GATEWAY, None)),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_on_permanent_errors() {
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&instant_policy(3), "Test", || {
+            calls.set(calls.get() + 1);
+            async { Err(status_error(StatusCode::BAD_REQUEST, None)) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_on_a_too_long_retry_after() {
+        let policy = RetryPolicy {
+            max_retry_after_ms: 1000,
+            ..instant_policy(3)
+        };
+        let calls = Cell::new(0);
+        let result: Result<()> = retry(&policy, "Test", || {
+            calls.set(calls.get() + 1);
+            async {
+                Err(status_error(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some(Duration::from_secs(60)),
+                ))
+            }
+        })
+        .await;
+        let err = result.unwrap_err();
+        assert_eq!(calls.get(), 1);
+        assert!(format!("{:#}", err).contains("max_retry_after_ms"));
+    }
+}
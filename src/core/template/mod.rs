@@ -0,0 +1,327 @@
+use crate::config::models::ReminderRule;
+use crate::core::wacraft::models::{
+    Contact, Conversation, MessagingProductContact, WhatsAppProductDetails,
+};
+use anyhow::{Context, Result};
+use chrono::format::StrftimeItems;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use minijinja::{
+    AutoEscape, Environment, Error, ErrorKind, Output, State, UndefinedBehavior, Value,
+};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::sync::OnceLock;
+
+/// How rendered values are escaped, depending on where the output ends up.
+/// Literal template text is never escaped, only the values inserted into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    /// Plain text, e.g. email subjects, headers and WhatsApp messages.
+    None,
+    /// HTML email bodies.
+    Html,
+    /// URL components.
+    Url,
+}
+
+/// Everything a reminder template can refer to.
+///
+/// Besides the structured fields, the flat `contact_id`, `contact_name` and
+/// `contact_email` values keep the original `{contact_name}`-style placeholders working.
+#[derive(Serialize, Debug)]
+pub struct TemplateContext<'a> {
+    pub contact: &'a Contact,
+    pub messaging_product_contact: &'a MessagingProductContact,
+    pub phone_number: Option<&'a WhatsAppProductDetails>,
+    /// The contact's latest message, when it is known.
+    pub conversation: Option<&'a Conversation>,
+    pub rule: RuleInfo<'a>,
+    pub inactivity: Inactivity,
+    /// Names of the sequence steps already delivered in this inactivity episode.
+    pub delivered_steps: Vec<&'a str>,
+    pub now: DateTime<Utc>,
+    pub today: NaiveDate,
+
+    pub contact_id: &'a str,
+    pub contact_name: &'a str,
+    pub contact_email: &'a str,
+}
+
+/// The rule being applied.
+#[derive(Serialize, Debug)]
+pub struct RuleInfo<'a> {
+    pub name: &'a str,
+    pub sequence: &'a str,
+    pub inactive_for_hours: u64,
+}
+
+/// How long the contact has been inactive, measured by the rule's basis.
+#[derive(Serialize, Debug)]
+pub struct Inactivity {
+    pub since: Option<DateTime<Utc>>,
+    pub minutes: i64,
+    pub hours: i64,
+    pub days: i64,
+}
+
+impl<'a> TemplateContext<'a> {
+    pub fn new(
+        contact: &'a MessagingProductContact,
+        details: &'a Contact,
+        conversation: Option<&'a Conversation>,
+        rule: &'a ReminderRule,
+        since: Option<DateTime<Utc>>,
+        delivered_steps: Vec<&'a str>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let inactive = since.map_or(Duration::zero(), |at| now.signed_duration_since(at));
+        Self {
+            contact: details,
+            messaging_product_contact: contact,
+            phone_number: contact.product_details.as_ref(),
+            conversation,
+            rule: RuleInfo {
+                name: &rule.name,
+                sequence: rule.sequence_name(),
+                inactive_for_hours: rule.inactive_for_hours,
+            },
+            inactivity: Inactivity {
+                since,
+                minutes: inactive.num_minutes(),
+                hours: inactive.num_hours(),
+                days: inactive.num_days(),
+            },
+            delivered_steps,
+            now,
+            today: now.date_naive(),
+            contact_id: &details.id,
+            contact_name: &details.name,
+            contact_email: details.email.as_deref().unwrap_or(""),
+        }
+    }
+
+    /// Renders a template, escaping inserted values for the output context.
+    pub fn render(&self, source: &str, escape: Escape) -> Result<String> {
+        let source = upgrade_legacy_placeholders(source);
+        environment(escape)
+            .render_str(&source, self)
+            .with_context(|| format!("Failed to render template: {}", source))
+    }
+
+    /// Renders a JSON document template, such as an HTTP request body.
+    ///
+    /// The template is walked as a tree and every string (including object keys) is
+    /// rendered on its own, so inserted values never need JSON escaping. A string that
+    /// is exactly one expression, e.g. `"{{ contact }}"` or `"{{ inactivity.days }}"`,
+    /// is replaced by the expression's value with its type preserved.
+    pub fn render_json(&self, template: &JsonValue) -> Result<JsonValue> {
+        Ok(match template {
+            JsonValue::String(source) => self.render_json_string(source)?,
+            JsonValue::Array(items) => JsonValue::Array(
+                items
+                    .iter()
+                    .map(|item| self.render_json(item))
+                    .collect::<Result<_>>()?,
+            ),
+            JsonValue::Object(fields) => JsonValue::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((self.render(key, Escape::None)?, self.render_json(value)?))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            other => other.clone(),
+        })
+    }
+
+    fn render_json_string(&self, source: &str) -> Result<JsonValue> {
+        let upgraded = upgrade_legacy_placeholders(source);
+        let Some(expression) = sole_expression(&upgraded) else {
+            return Ok(JsonValue::String(self.render(source, Escape::None)?));
+        };
+        let value = environment(Escape::None)
+            .compile_expression(expression)
+            .and_then(|expr| expr.eval(self))
+            .with_context(|| format!("Failed to evaluate template: {}", source))?;
+        if value.is_undefined() {
+            return Ok(JsonValue::Null);
+        }
+        serde_json::to_value(&value)
+            .with_context(|| format!("Failed to convert the value of {} to JSON", source))
+    }
+}
+
+/// Returns the inner expression of a template made of a single `{{ ... }}` block.
+fn sole_expression(source: &str) -> Option<&str> {
+    let inner = source
+        .trim()
+        .strip_prefix("{{")?
+        .strip_suffix("}}")?
+        .trim_start_matches('-')
+        .trim_end_matches('-');
+    (!inner.contains("{{") && !inner.contains("}}")).then_some(inner.trim())
+}
+
+/// Characters left alone when URL-encoding: the RFC 3986 unreserved set.
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Rewrites the original single-brace placeholders (`{contact_name}`) into
+/// template expressions (`{{ contact_name }}`).
+fn upgrade_legacy_placeholders(source: &str) -> Cow<'_, str> {
+    static LEGACY: OnceLock<Regex> = OnceLock::new();
+    let legacy = LEGACY
+        .get_or_init(|| Regex::new(r"(\{+)(contact_id|contact_name|contact_email)(\}+)").unwrap());
+    legacy.replace_all(source, |caps: &regex::Captures| {
+        if caps[1].len() == 1 && caps[3].len() == 1 {
+            format!("{{{{ {} }}}}", &caps[2])
+        } else {
+            caps[0].to_string()
+        }
+    })
+}
+
+/// Returns the shared template environment for an escaping mode.
+fn environment(escape: Escape) -> &'static Environment<'static> {
+    static NONE: OnceLock<Environment<'static>> = OnceLock::new();
+    static HTML: OnceLock<Environment<'static>> = OnceLock::new();
+    static URL: OnceLock<Environment<'static>> = OnceLock::new();
+    let cell = match escape {
+        Escape::None => &NONE,
+        Escape::Html => &HTML,
+        Escape::Url => &URL,
+    };
+    cell.get_or_init(|| build_environment(escape))
+}
+
+fn build_environment(escape: Escape) -> Environment<'static> {
+    let mut env = Environment::new();
+    // Optional data (e.g. `conversation`) may be missing; render it as empty text.
+    env.set_undefined_behavior(UndefinedBehavior::Chainable);
+    env.set_auto_escape_callback(move |_| match escape {
+        Escape::Html => AutoEscape::Html,
+        _ => AutoEscape::None,
+    });
+    env.set_formatter(move |out, state, value| format_value(escape, out, state, value));
+    env.add_filter("date", date_filter);
+    env
+}
+
+/// Writes a value into the template output. Missing values render as empty text.
+fn format_value(
+    escape: Escape,
+    out: &mut Output,
+    state: &State,
+    value: &Value,
+) -> Result<(), Error> {
+    if value.is_undefined() || value.is_none() {
+        return Ok(());
+    }
+    if value.is_safe() {
+        return write!(out, "{}", value).map_err(Error::from);
+    }
+    match escape {
+        Escape::None => write!(out, "{}", value)?,
+        Escape::Html => minijinja::escape_formatter(out, state, value)?,
+        Escape::Url => write!(
+            out,
+            "{}",
+            utf8_percent_encode(&value.to_string(), URL_COMPONENT)
+        )?,
+    }
+    Ok(())
+}
+
+/// `{{ now | date("%d/%m/%Y") }}`: formats an RFC 3339 timestamp (the format all
+/// timestamps in the context use) or a plain `YYYY-MM-DD` date such as `today`.
+/// Defaults to `%Y-%m-%d`.
+fn date_filter(value: String, format: Option<String>) -> Result<String, Error> {
+    let format = format.as_deref().unwrap_or("%Y-%m-%d");
+    let items = StrftimeItems::new(format);
+    let formatted = match DateTime::parse_from_rfc3339(&value) {
+        Ok(at) => at.format_with_items(items),
+        Err(_) => NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidOperation,
+                    format!("'{}' is not a date or timestamp", value),
+                )
+            })?
+            .format_with_items(items),
+    };
+    // Invalid specifiers, or time fields on a plain date, fail here instead of panicking.
+    let mut out = String::new();
+    write!(out, "{}", formatted).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidOperation,
+            format!("'{}' is not a valid date format for '{}'", format, value),
+        )
+    })?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_filter_formats_timestamps() {
+        let formatted = date_filter(
+            "2026-03-09T14:05:00Z".to_string(),
+            Some("%d/%m %H:%M".into()),
+        )
+        .unwrap();
+        assert_eq!(formatted, "09/03 14:05");
+    }
+
+    #[test]
+    fn date_filter_formats_plain_dates() {
+        assert_eq!(
+            date_filter("2026-03-09".to_string(), Some("%d/%m".into())).unwrap(),
+            "09/03"
+        );
+        assert_eq!(
+            date_filter("2026-03-09".to_string(), None).unwrap(),
+            "2026-03-09"
+        );
+    }
+
+    #[test]
+    fn date_filter_rejects_bad_input() {
+        assert!(date_filter("yesterday".to_string(), None).is_err());
+        // A plain date has no time to format.
+        assert!(date_filter("2026-03-09".to_string(), Some("%H:%M".into())).is_err());
+    }
+
+    #[test]
+    fn legacy_placeholders_are_upgraded() {
+        assert_eq!(
+            upgrade_legacy_placeholders("Hi {contact_name}, your ID is {contact_id}."),
+            "Hi {{ contact_name }}, your ID is {{ contact_id }}."
+        );
+        assert_eq!(
+            upgrade_legacy_placeholders("mailto:{contact_email}"),
+            "mailto:{{ contact_email }}"
+        );
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        for source in [
+            "Hi {{ contact_name }}",
+            "Hi {{contact_name}}",
+            "{unknown}",
+            "{\"json\": true}",
+        ] {
+            assert_eq!(upgrade_legacy_placeholders(source), source);
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Spaces out operations to stay under a rate limit, e.g. messages per minute.
+/// Shared by concurrent tasks, which each wait for their own slot.
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    next_slot: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// Allows at most `limit` operations per `period`. No limit applies when it is zero.
+    pub fn new(limit: u32, period: Duration) -> Option<Self> {
+        (limit > 0).then(|| Self {
+            interval: period / limit,
+            next_slot: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Waits until the next free slot and reserves it.
+    pub async fn wait(&self) {
+        let slot = {
+            let mut next_slot = self.next_slot.lock().await;
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
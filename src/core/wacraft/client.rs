@@ -1,48 +1,65 @@
-use crate::config::models::WacraftConfig;
+use crate::config::models::{RetryPolicy, WacraftConfig};
+use crate::core::retry::{HttpStatusError, retry};
+use crate::core::throttle::Throttle;
 use crate::core::wacraft::models::{
     Conversation, SendWhatsAppMessage, TokenRequest, TokenResponse,
 };
-use anyhow::{Context, Result, anyhow};
-use log::{debug, info};
-use reqwest::Client;
+use crate::store::tokens::{self, CachedTokens};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use reqwest::{Client, RequestBuilder, Response};
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 
-use super::models::{MessagingProductContact, Order};
+use super::models::{ContactActivity, MessagingProductContact, Order};
+
+/// Page size used when scanning a contact's message history.
+const ACTIVITY_PAGE_SIZE: u32 = 50;
+/// How many pages of history to scan before giving up on finding a direction.
+const ACTIVITY_MAX_PAGES: u32 = 5;
 
 /// A client for interacting with the Wacraft API, with built-in token management.
+///
+/// Clones share the token state and the request rate limit, so a single client can
+/// be handed to every task of a daemon cycle.
 #[derive(Debug, Clone)]
 pub struct WacraftClient {
     http_client: Client,
     // Use an Arc<RwLock<>> to allow for safe, concurrent access and modification of the config.
     // This is crucial for managing token state across multiple async tasks.
     config: Arc<RwLock<WacraftConfig>>,
+    // Spaces out requests to honor `max_requests_per_second`.
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl WacraftClient {
     /// Creates a new Wacraft API client.
     pub fn new(config: WacraftConfig) -> Self {
+        let throttle = Throttle::new(config.max_requests_per_second, Duration::from_secs(1));
         Self {
             http_client: Client::new(),
             config: Arc::new(RwLock::new(config)),
+            throttle: throttle.map(Arc::new),
+        }
+    }
+
+    /// Waits until the rate limit allows another request.
+    async fn _throttle(&self) {
+        if let Some(throttle) = &self.throttle {
+            throttle.wait().await;
         }
     }
 
     /// Retrieves a valid access token. It handles token expiration and refreshing automatically.
+    /// Tokens are shared with other processes through the on-disk token cache, so the
+    /// password grant is only used when no usable token or refresh token exists.
     pub async fn get_valid_token(&self) -> Result<String> {
         // First, try to get a read lock to check the current token.
         let config_read_guard = self.config.read().await;
-        if let (Some(token), Some(expires_at)) = (
-            &config_read_guard.access_token,
-            config_read_guard.token_expires_at,
-        ) {
-            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-            // Check if the token is valid for at least another 60 seconds.
-            if expires_at > now + 60 {
-                debug!("Using existing, valid access token.");
-                return Ok(token.clone());
-            }
+        if let Some(token) = Self::_valid_access_token(&config_read_guard)? {
+            debug!("Using existing, valid access token.");
+            return Ok(token);
         }
         // Drop the read lock so we can acquire a write lock later if needed.
         drop(config_read_guard);
@@ -54,15 +71,27 @@ impl WacraftClient {
 
         // Re-check the token validity after acquiring the write lock.
         // Another task might have refreshed it while we were waiting for the lock.
-        if let (Some(token), Some(expires_at)) = (
-            &config_write_guard.access_token,
-            config_write_guard.token_expires_at,
-        ) {
-            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-            if expires_at > now + 60 {
-                debug!("Token was refreshed by another task. Using new token.");
-                return Ok(token.clone());
-            }
+        if let Some(token) = Self::_valid_access_token(&config_write_guard)? {
+            debug!("Token was refreshed by another task. Using new token.");
+            return Ok(token);
+        }
+
+        // Another process (e.g. the daemon) may have cached a fresh token.
+        if let Some(token) = self._adopt_cached_tokens(&mut config_write_guard).await? {
+            debug!("Using access token from the token cache.");
+            return Ok(token);
+        }
+
+        // Only one process at a time may refresh, since refresh tokens can be
+        // single-use. The lock is blocking, so take it off the async runtime.
+        let _cache_lock = tokio::task::spawn_blocking(tokens::lock)
+            .await
+            .context("Token cache lock task failed")??;
+
+        // Re-check the cache: another process may have refreshed while we waited.
+        if let Some(token) = self._adopt_cached_tokens(&mut config_write_guard).await? {
+            debug!("Token was refreshed by another process. Using cached token.");
+            return Ok(token);
         }
 
         // --- Perform Token Refresh ---
@@ -76,11 +105,9 @@ impl WacraftClient {
                 password: None,
                 refresh_token: Some(&refresh_token),
             };
-            if let Ok(response) = self
-                ._get_token(&request, Some(&config_write_guard.base_url))
-                .await
-            {
-                self._update_config_tokens(&mut config_write_guard, response);
+            if let Ok(response) = self._get_token(&request, &config_write_guard).await {
+                self._update_config_tokens(&mut config_write_guard, response)
+                    .await;
                 info!("Successfully refreshed access token.");
                 return Ok(config_write_guard.access_token.clone().unwrap());
             }
@@ -97,44 +124,71 @@ impl WacraftClient {
 
         debug!("Executing get token request...");
         let response = self
-            ._get_token(&request, Some(&config_write_guard.base_url))
+            ._get_token(&request, &config_write_guard)
             .await
             .context("Failed to get token with password credentials")?;
         debug!("Successfully executed get token request!");
 
-        self._update_config_tokens(&mut config_write_guard, response);
+        self._update_config_tokens(&mut config_write_guard, response)
+            .await;
         info!("Successfully obtained new access token using password.");
         Ok(config_write_guard.access_token.clone().unwrap())
     }
 
+    /// Returns the configured access token if it is valid for at least another 60 seconds.
+    fn _valid_access_token(config: &WacraftConfig) -> Result<Option<String>> {
+        if let (Some(token), Some(expires_at)) = (&config.access_token, config.token_expires_at) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+            if expires_at > now + 60 {
+                return Ok(Some(token.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Loads the token cache into the config. Returns the cached access token if it
+    /// is still valid. Otherwise only the cached refresh token is taken over, as it
+    /// is the most recently issued one.
+    ///
+    /// Reading the file blocks, and other tasks wait on the config lock meanwhile,
+    /// so it happens off the async runtime.
+    async fn _adopt_cached_tokens(&self, config: &mut WacraftConfig) -> Result<Option<String>> {
+        let (base_url, email) = (config.base_url.clone(), config.email.clone());
+        let cached = tokio::task::spawn_blocking(move || tokens::load(&base_url, &email))
+            .await
+            .context("Token cache read task failed")??;
+        let Some(cached) = cached else {
+            return Ok(None);
+        };
+        config.refresh_token = Some(cached.refresh_token);
+        config.access_token = Some(cached.access_token);
+        config.token_expires_at = Some(cached.token_expires_at);
+        Self::_valid_access_token(config)
+    }
+
     /// Internal function to request a token from the `/user/oauth/token` endpoint.
+    /// Takes the config from the caller, who holds the write lock during refreshes.
     async fn _get_token(
         &self,
         request_body: &TokenRequest<'_>,
-        base_url: Option<&str>,
+        config: &WacraftConfig,
     ) -> Result<TokenResponse> {
-        let api_base_url = if let Some(url) = base_url {
-            url.to_string()
-        } else {
-            self.config.read().await.base_url.clone()
-        };
-        let url = format!("{}/user/oauth/token", api_base_url);
-        let response = self
-            .http_client
-            .post(&url)
-            .json(request_body)
-            .send()
-            .await
-            .context("Failed to send token request to Wacraft API")?;
-        let status = response.status();
-        if !status.is_success() {
-            let error_body = response.text().await?;
-            return Err(anyhow!(
-                "Token request failed. Status: {}, Body: {}",
-                status,
-                error_body
-            ));
-        }
+        let url = format!("{}/user/oauth/token", config.base_url);
+        let policy = config.retry.for_call("token");
+        let response = retry(policy, "Wacraft token request", || async {
+            self._throttle().await;
+            let response = self
+                .http_client
+                .post(&url)
+                .json(request_body)
+                .send()
+                .await
+                .context("Failed to send token request to Wacraft API")?;
+            HttpStatusError::check(response)
+                .await
+                .context("Token request failed")
+        })
+        .await?;
 
         response
             .json::<TokenResponse>()
@@ -142,16 +196,63 @@ impl WacraftClient {
             .context("Failed to parse token response")
     }
 
+    /// Sends an authenticated request built by `build` from the base URL and checks
+    /// its status. Transient failures are retried according to `policy`.
+    async fn _send(
+        &self,
+        call: &str,
+        policy: &RetryPolicy,
+        build: impl Fn(&Client, &str) -> RequestBuilder,
+    ) -> Result<Response> {
+        retry(policy, &format!("Wacraft '{}' call", call), || async {
+            let token = self.get_valid_token().await?;
+            let base_url = self.config.read().await.base_url.clone();
+            self._throttle().await;
+            let response = build(&self.http_client, &base_url)
+                .bearer_auth(token)
+                .send()
+                .await
+                .with_context(|| format!("Failed to send '{}' request to Wacraft API", call))?;
+            Ok(HttpStatusError::check(response).await?)
+        })
+        .await
+    }
+
+    /// Returns the retry policy for an API call.
+    async fn _retry_policy(&self, call: &str) -> RetryPolicy {
+        self.config.read().await.retry.for_call(call).clone()
+    }
+
     /// Helper function to update the config with new token data.
-    fn _update_config_tokens(&self, config: &mut WacraftConfig, response: TokenResponse) {
+    async fn _update_config_tokens(&self, config: &mut WacraftConfig, response: TokenResponse) {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs() as i64;
-        config.access_token = Some(response.access_token);
-        config.refresh_token = Some(response.refresh_token);
+        config.access_token = Some(response.access_token.clone());
+        config.refresh_token = Some(response.refresh_token.clone());
         config.token_expires_at = Some(now + response.expires_in);
-        // TODO: Persist the updated config to `settings.json`
+
+        // Persist the tokens so other runs and processes can reuse them.
+        // A failure here only costs an extra login later, so don't fail the request.
+        let cached = CachedTokens {
+            base_url: config.base_url.clone(),
+            email: config.email.clone(),
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            token_expires_at: now + response.expires_in,
+        };
+        // Writing blocks, so take it off the async runtime like the cache lock.
+        let saved = tokio::task::spawn_blocking(move || tokens::save(&cached))
+            .await
+            .context("Token cache write task failed")
+            .and_then(|result| result);
+        if let Err(e) = saved {
+            warn!(
+                "Failed to persist Wacraft tokens to the token cache: {:?}",
+                e
+            );
+        }
     }
 
     // --- Public API Methods ---
@@ -161,27 +262,20 @@ impl WacraftClient {
         let payload_json = serde_json::to_string_pretty(&message)?;
         info!("Sending Wacraft message with payload:\n{}", payload_json);
 
-        let token = self.get_valid_token().await?;
-        let url = format!("{}/message/whatsapp", self.config.read().await.base_url);
-
-        let response = self
-            .http_client
-            .post(&url)
-            .bearer_auth(token)
-            .json(message)
-            .send()
-            .await
-            .context("Failed to send 'send_message' request to Wacraft API")?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await?;
-            anyhow::bail!(
-                "Failed to send WhatsApp message. Status: {}, Body: {}",
-                status,
-                error_body
-            );
-        }
+        // Sends are retried by the `wacraft_message` action policy, not here, so
+        // that attempts don't multiply. A `send_message` entry in `wacraft.retry`
+        // is reported as ignored when the settings are loaded.
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        self._send("send_message", &policy, |client, base_url| {
+            client
+                .post(format!("{}/message/whatsapp", base_url))
+                .json(message)
+        })
+        .await
+        .context("Failed to send WhatsApp message")?;
 
         Ok(())
     }
@@ -192,33 +286,25 @@ impl WacraftClient {
         limit: u32,
         offset: u32,
         created_at_leq: Option<&str>,
+        created_at_order: Option<Order>,
     ) -> Result<Vec<Conversation>> {
-        let token = self.get_valid_token().await?;
-        let url = format!("{}/message/conversation", self.config.read().await.base_url);
-
         let mut query_params = vec![("limit", limit.to_string()), ("offset", offset.to_string())];
         if let Some(date) = created_at_leq {
             query_params.push(("created_at_leq", date.to_string()));
         }
+        if let Some(order) = created_at_order {
+            query_params.push(("created_at", order.to_string()));
+        }
 
+        let policy = self._retry_policy("get_conversations").await;
         let response = self
-            .http_client
-            .get(&url)
-            .bearer_auth(token)
-            .query(&query_params)
-            .send()
+            ._send("get_conversations", &policy, |client, base_url| {
+                client
+                    .get(format!("{}/message/conversation", base_url))
+                    .query(&query_params)
+            })
             .await
-            .context("Failed to send 'get_conversations' request to Wacraft API")?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await?;
-            anyhow::bail!(
-                "Failed to fetch conversations. Status: {}, Body: {}",
-                status,
-                error_body
-            );
-        }
+            .context("Failed to fetch conversations")?;
 
         response
             .json::<Vec<Conversation>>()
@@ -236,13 +322,6 @@ impl WacraftClient {
         created_at_order: Option<Order>,
         updated_at_order: Option<Order>,
     ) -> Result<Vec<Conversation>> {
-        let token = self.get_valid_token().await?;
-        let url = format!(
-            "{}/message/conversation/messaging-product-contact/{}",
-            self.config.read().await.base_url,
-            contact_id
-        );
-
         let mut query_params = vec![("limit", limit.to_string()), ("offset", offset.to_string())];
         if let Some(date) = created_at_leq {
             query_params.push(("created_at_leq", date.to_string()));
@@ -254,24 +333,18 @@ impl WacraftClient {
             query_params.push(("updated_at", order.to_string()));
         }
 
+        let policy = self._retry_policy("get_conversation_messages").await;
         let response = self
-            .http_client
-            .get(&url)
-            .bearer_auth(token)
-            .query(&query_params)
-            .send()
+            ._send("get_conversation_messages", &policy, |client, base_url| {
+                client
+                    .get(format!(
+                        "{}/message/conversation/messaging-product-contact/{}",
+                        base_url, contact_id
+                    ))
+                    .query(&query_params)
+            })
             .await
-            .context("Failed to send 'get_conversation_messages' request to Wacraft API")?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await?;
-            anyhow::bail!(
-                "Failed to fetch conversations. Status: {}, Body: {}",
-                status,
-                error_body
-            );
-        }
+            .context("Failed to fetch conversation messages")?;
 
         response
             .json::<Vec<Conversation>>()
@@ -284,32 +357,19 @@ impl WacraftClient {
         &self,
         contact_id: &str,
     ) -> Result<Option<MessagingProductContact>> {
-        let token = self.get_valid_token().await?;
-        let url = format!(
-            "{}/messaging-product/contact",
-            self.config.read().await.base_url
-        );
-
+        let policy = self._retry_policy("get_messaging_product_contact").await;
         let response = self
-            .http_client
-            .get(&url)
-            .bearer_auth(token)
-            .query(&[("id", contact_id), ("limit", "1"), ("offset", "0")])
-            .send()
+            ._send(
+                "get_messaging_product_contact",
+                &policy,
+                |client, base_url| {
+                    client
+                        .get(format!("{}/messaging-product/contact", base_url))
+                        .query(&[("id", contact_id), ("limit", "1"), ("offset", "0")])
+                },
+            )
             .await
-            .context(
-                "Failed to send 'get_messaging_product_contact_by_id' request to Wacraft API",
-            )?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_body = response.text().await?;
-            anyhow::bail!(
-                "Failed to fetch messaging product contact. Status: {}, Body: {}",
-                status,
-                error_body
-            );
-        }
+            .context("Failed to fetch messaging product contact")?;
 
         let mut contacts = response
             .json::<Vec<MessagingProductContact>>()
@@ -323,4 +383,34 @@ impl WacraftClient {
         // The API returns an array, so we take the first element if it exists.
         Ok(contact)
     }
+
+    /// Scans the contact's most recent messages to find when they last wrote to us
+    /// and when we last wrote to them. Stops once both directions are found or
+    /// after a bounded number of pages.
+    pub async fn get_contact_activity(&self, contact_id: &str) -> Result<ContactActivity> {
+        let mut activity = ContactActivity::default();
+
+        for page in 0..ACTIVITY_MAX_PAGES {
+            let messages = self
+                .get_conversation_messages(
+                    contact_id,
+                    ACTIVITY_PAGE_SIZE,
+                    page * ACTIVITY_PAGE_SIZE,
+                    None,
+                    Some(Order::Desc),
+                    None,
+                )
+                .await?;
+
+            for message in &messages {
+                activity.observe(message, contact_id);
+            }
+
+            if activity.is_complete() || (messages.len() as u32) < ACTIVITY_PAGE_SIZE {
+                break;
+            }
+        }
+
+        Ok(activity)
+    }
 }
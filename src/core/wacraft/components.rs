@@ -1,5 +1,15 @@
+use crate::core::template::{Escape, TemplateContext};
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+/// Renders an optional text field as a plain-text template.
+fn render_opt(ctx: &TemplateContext<'_>, value: &Option<String>) -> Result<Option<String>> {
+    value
+        .as_deref()
+        .map(|v| ctx.render(v, Escape::None))
+        .transpose()
+}
+
 /// Represents a simple text message payload.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TextData {
@@ -8,6 +18,16 @@ pub struct TextData {
     pub preview_url: Option<bool>,
 }
 
+impl TextData {
+    /// Renders the message body for a contact.
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        Ok(Self {
+            body: ctx.render(&self.body, Escape::None)?,
+            preview_url: self.preview_url,
+        })
+    }
+}
+
 /// Represents a media object (image, video, document) to be sent.
 /// You can use either a public link or an ID of a previously uploaded media file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -22,6 +42,17 @@ pub struct UseMedia {
     pub filename: Option<String>,
 }
 
+impl UseMedia {
+    /// Renders the caption and filename for a contact.
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        Ok(Self {
+            caption: render_opt(ctx, &self.caption)?,
+            filename: render_opt(ctx, &self.filename)?,
+            ..self.clone()
+        })
+    }
+}
+
 /// Represents a message template to be used.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UseTemplate {
@@ -31,6 +62,22 @@ pub struct UseTemplate {
     pub components: Option<Vec<Component>>,
 }
 
+impl UseTemplate {
+    /// Renders the component parameters for a contact, e.g. the body variable
+    /// `{{1}}` of an approved template can be set to `{{ contact.name }}`.
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        let components = self
+            .components
+            .as_ref()
+            .map(|components| components.iter().map(|c| c.render(ctx)).collect())
+            .transpose()?;
+        Ok(Self {
+            components,
+            ..self.clone()
+        })
+    }
+}
+
 /// Specifies the language of the template.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Language {
@@ -47,6 +94,20 @@ pub struct Component {
     // ... other component fields like sub_type and index can be added if needed.
 }
 
+impl Component {
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        let parameters = self
+            .parameters
+            .as_ref()
+            .map(|parameters| parameters.iter().map(|p| p.render(ctx)).collect())
+            .transpose()?;
+        Ok(Self {
+            component_type: self.component_type.clone(),
+            parameters,
+        })
+    }
+}
+
 /// A parameter for a template component, allowing for dynamic content.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Parameter {
@@ -61,6 +122,17 @@ pub struct Parameter {
     // ... other parameter types like video, currency, etc. can be added.
 }
 
+impl Parameter {
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        Ok(Self {
+            parameter_type: self.parameter_type.clone(),
+            text: render_opt(ctx, &self.text)?,
+            image: self.image.as_ref().map(|m| m.render(ctx)).transpose()?,
+            document: self.document.as_ref().map(|m| m.render(ctx)).transpose()?,
+        })
+    }
+}
+
 // NOTE: Interactive messages have a very complex structure.
 // For now, we'll stub it out. It can be fully implemented if needed.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -69,3 +141,13 @@ pub struct Interactive {
     pub action: serde_json::Value,
     pub body: TextData,
 }
+
+impl Interactive {
+    /// Renders the body text for a contact. The action (buttons, lists) is sent as-is.
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        Ok(Self {
+            action: self.action.clone(),
+            body: self.body.render(ctx)?,
+        })
+    }
+}
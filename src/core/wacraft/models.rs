@@ -1,5 +1,8 @@
 // Contains the primary data structures for Wacraft API requests and responses.
 use super::components::{Interactive, TextData, UseMedia, UseTemplate};
+use crate::config::models::InactivityBasis;
+use crate::core::template::TemplateContext;
+use anyhow::Result;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -41,6 +44,48 @@ pub struct MessagePayloadBase {
     pub interactive: Option<Interactive>,
 }
 
+impl MessagePayloadBase {
+    /// Returns whether this is a free-form message, which WhatsApp only delivers
+    /// inside the customer-service window. Only templates may be sent outside it.
+    pub fn is_free_form(&self) -> bool {
+        self.message_type != "template"
+    }
+
+    /// Builds a template message for the same product and recipient type.
+    pub fn with_template(&self, template: UseTemplate) -> Self {
+        Self {
+            messaging_product: self.messaging_product.clone(),
+            recipient_type: self.recipient_type.clone(),
+            message_type: "template".to_string(),
+            template: Some(template),
+            ..Default::default()
+        }
+    }
+
+    /// Renders the payload's texts, media captions and filenames, and template
+    /// parameters for a contact.
+    pub fn render(&self, ctx: &TemplateContext<'_>) -> Result<Self> {
+        let media = |m: &Option<UseMedia>| m.as_ref().map(|m| m.render(ctx)).transpose();
+        Ok(Self {
+            messaging_product: self.messaging_product.clone(),
+            recipient_type: self.recipient_type.clone(),
+            message_type: self.message_type.clone(),
+            text: self.text.as_ref().map(|t| t.render(ctx)).transpose()?,
+            image: media(&self.image)?,
+            document: media(&self.document)?,
+            audio: media(&self.audio)?,
+            video: media(&self.video)?,
+            sticker: media(&self.sticker)?,
+            template: self.template.as_ref().map(|t| t.render(ctx)).transpose()?,
+            interactive: self
+                .interactive
+                .as_ref()
+                .map(|i| i.render(ctx))
+                .transpose()?,
+        })
+    }
+}
+
 /// Represents the `sender_data` part of the request
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct MessagePayload {
@@ -70,6 +115,108 @@ pub struct Conversation {
     pub deleted_at: Option<DateTime<Utc>>,
 }
 
+/// How long after a contact's last inbound message WhatsApp accepts free-form messages.
+pub const CUSTOMER_SERVICE_WINDOW_HOURS: i64 = 24;
+
+/// The ID Wacraft uses for "no contact", i.e. our own side of a conversation.
+pub const NIL_UUID: &str = "00000000-0000-0000-0000-000000000000";
+
+/// The direction of a message relative to a contact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by the contact to us.
+    Inbound,
+    /// Sent by us to the contact.
+    Outbound,
+}
+
+impl Conversation {
+    /// Returns the ID of the messaging product contact on the other side of this
+    /// message, regardless of whether they sent it or received it.
+    pub fn contact_id(&self) -> Option<&str> {
+        let is_contact = |id: &&str| *id != NIL_UUID;
+        self.to_id
+            .as_deref()
+            .filter(is_contact)
+            .or_else(|| self.from_id.as_deref().filter(is_contact))
+    }
+
+    /// Returns the embedded messaging product contact for `contact_id`, if the
+    /// API included it on either side of the message.
+    pub fn contact(&self, contact_id: &str) -> Option<&MessagingProductContact> {
+        [&self.from_contact, &self.to_contact]
+            .into_iter()
+            .flatten()
+            .find(|c| c.id == contact_id)
+    }
+
+    /// Returns whether this message was sent by or to `contact_id`.
+    pub fn direction_for(&self, contact_id: &str) -> Option<Direction> {
+        if self.from_id.as_deref() == Some(contact_id) {
+            Some(Direction::Inbound)
+        } else if self.to_id.as_deref() == Some(contact_id) {
+            Some(Direction::Outbound)
+        } else {
+            None
+        }
+    }
+}
+
+/// When a contact last exchanged messages with us, split by direction.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ContactActivity {
+    pub last_inbound_at: Option<DateTime<Utc>>,
+    pub last_outbound_at: Option<DateTime<Utc>>,
+}
+
+impl ContactActivity {
+    /// Folds a message into the activity, keeping the latest time per direction.
+    pub fn observe(&mut self, message: &Conversation, contact_id: &str) {
+        let slot = match message.direction_for(contact_id) {
+            Some(Direction::Inbound) => &mut self.last_inbound_at,
+            Some(Direction::Outbound) => &mut self.last_outbound_at,
+            None => return,
+        };
+        if slot.is_none_or(|at| at < message.created_at) {
+            *slot = Some(message.created_at);
+        }
+    }
+
+    /// Returns the latest message time in either direction.
+    pub fn last_message_at(&self) -> Option<DateTime<Utc>> {
+        self.last_inbound_at.max(self.last_outbound_at)
+    }
+
+    /// Returns the time inactivity is measured from for the given basis.
+    pub fn since(&self, basis: InactivityBasis) -> Option<DateTime<Utc>> {
+        match basis {
+            InactivityBasis::Inbound => self.last_inbound_at,
+            InactivityBasis::Outbound => self.last_outbound_at,
+            InactivityBasis::Any => self.last_message_at(),
+        }
+    }
+
+    /// Returns the start of the current inactivity episode: the contact's last
+    /// inbound message, or the Unix epoch if they never wrote to us.
+    /// Reminder sequences restart whenever this changes.
+    pub fn episode_start(&self) -> DateTime<Utc> {
+        self.last_inbound_at.unwrap_or(DateTime::UNIX_EPOCH)
+    }
+
+    /// Returns whether the contact's WhatsApp customer-service window is open,
+    /// i.e. they wrote to us less than 24 hours before `now`.
+    pub fn in_customer_service_window(&self, now: DateTime<Utc>) -> bool {
+        self.last_inbound_at.is_some_and(|at| {
+            now.signed_duration_since(at) < chrono::Duration::hours(CUSTOMER_SERVICE_WINDOW_HOURS)
+        })
+    }
+
+    /// Returns whether both directions have been seen.
+    pub fn is_complete(&self) -> bool {
+        self.last_inbound_at.is_some() && self.last_outbound_at.is_some()
+    }
+}
+
 /// Represents a contact linked to a messaging product (e.g., a WhatsApp user).
 /// Based on `messaging_product_entity.MessagingProductContact`.
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -107,6 +254,7 @@ pub struct WhatsAppProductDetails {
 
 /// Represents the `product_data` field within a received message, which holds the
 /// actual content from the WhatsApp webhook. Based on `message_model.ReceiverData`.
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProductData {
     pub text: Option<TextData>,
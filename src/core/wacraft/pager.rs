@@ -0,0 +1,201 @@
+use crate::core::wacraft::client::WacraftClient;
+use crate::core::wacraft::models::{Conversation, Order};
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::info;
+use std::collections::HashSet;
+
+/// Pages through the conversations as they were at a fixed point in time.
+///
+/// Offset paging over a live list shifts whenever a conversation is updated, which
+/// skips some contacts and repeats others. Instead, every page is requested newest
+/// first with `created_at_leq` set to the creation time of the last conversation
+/// seen, so conversations created after the snapshot never enter the walk and the
+/// ones already visited can't shift the next page. The offset only steps over
+/// conversations that share the cursor's exact timestamp.
+pub struct ConversationPager<'a> {
+    client: &'a WacraftClient,
+    limit: u32,
+    /// The creation time of the last conversation returned, or the snapshot time.
+    cursor: DateTime<Utc>,
+    /// How many conversations created exactly at `cursor` were already returned.
+    skip: u32,
+    /// The contacts already returned, so each one is visited once per walk.
+    seen: HashSet<String>,
+    done: bool,
+}
+
+impl<'a> ConversationPager<'a> {
+    /// Starts a walk over the conversations created up to `snapshot`.
+    pub fn new(client: &'a WacraftClient, limit: u32, snapshot: DateTime<Utc>) -> Self {
+        Self {
+            client,
+            limit: limit.max(1),
+            cursor: snapshot,
+            skip: 0,
+            seen: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// Fetches the next page, leaving out contacts that were already returned.
+    /// Returns `None` once every conversation has been visited.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<Conversation>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let created_at_leq = self.cursor.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        info!(
+            "Fetching conversations batch: limit={}, created_at_leq={}, offset={}",
+            self.limit, created_at_leq, self.skip
+        );
+        let page = self
+            .client
+            .get_conversations(
+                self.limit,
+                self.skip,
+                Some(&created_at_leq),
+                Some(Order::Desc),
+            )
+            .await
+            .context("Failed to fetch conversations batch")?;
+
+        self.advance(&page);
+        if page.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(self.keep_unseen(page)))
+    }
+
+    /// Moves the cursor past a fetched page.
+    fn advance(&mut self, page: &[Conversation]) {
+        // A short page is the last one.
+        self.done = (page.len() as u32) < self.limit;
+        if let Some(last) = page.last() {
+            let at_last = page
+                .iter()
+                .rev()
+                .take_while(|conv| conv.created_at == last.created_at)
+                .count() as u32;
+            if last.created_at == self.cursor {
+                // The whole page shares the cursor's timestamp; step over it.
+                self.skip += at_last;
+            } else {
+                self.cursor = last.created_at;
+                self.skip = at_last;
+            }
+        }
+    }
+
+    /// Leaves out the conversations of contacts that were already returned.
+    fn keep_unseen(&mut self, page: Vec<Conversation>) -> Vec<Conversation> {
+        page.into_iter()
+            .filter(|conv| {
+                let key = conv.contact_id().unwrap_or(&conv.id);
+                self.seen.insert(key.to_string())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{at_minute, inbound};
+
+    fn client() -> WacraftClient {
+        WacraftClient::new(
+            serde_json::from_str(
+                r#"{"base_url": "http://localhost", "email": "", "password": ""}"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    /// Answers a page request like the API: newest first, ties in a stable order.
+    fn serve(
+        conversations: &[Conversation],
+        limit: u32,
+        offset: u32,
+        created_at_leq: DateTime<Utc>,
+    ) -> Vec<Conversation> {
+        let mut matching: Vec<Conversation> = conversations
+            .iter()
+            .filter(|conv| conv.created_at <= created_at_leq)
+            .cloned()
+            .collect();
+        matching.sort_by_key(|conv| std::cmp::Reverse(conv.created_at));
+        matching
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect()
+    }
+
+    /// Walks the conversations like `next_page`, returning the IDs in visiting order.
+    /// `between_pages` may change the conversations after each page.
+    fn walk(
+        conversations: &mut Vec<Conversation>,
+        limit: u32,
+        snapshot: DateTime<Utc>,
+        mut between_pages: impl FnMut(&mut Vec<Conversation>),
+    ) -> Vec<String> {
+        let client = client();
+        let mut pager = ConversationPager::new(&client, limit, snapshot);
+        let mut visited = Vec::new();
+        while !pager.done {
+            let page = serve(conversations, pager.limit, pager.skip, pager.cursor);
+            pager.advance(&page);
+            visited.extend(pager.keep_unseen(page).into_iter().map(|conv| conv.id));
+            between_pages(conversations);
+        }
+        visited
+    }
+
+    #[test]
+    fn visits_every_conversation_once() {
+        let mut conversations: Vec<Conversation> = (0..7)
+            .map(|i| inbound(&format!("m{}", i), &format!("c{}", i), at_minute(i)))
+            .collect();
+        let visited = walk(&mut conversations, 3, at_minute(60), |_| {});
+        assert_eq!(visited, ["m6", "m5", "m4", "m3", "m2", "m1", "m0"]);
+    }
+
+    #[test]
+    fn steps_over_conversations_sharing_a_timestamp() {
+        let mut conversations = vec![inbound("m9", "c9", at_minute(9))];
+        conversations
+            .extend((0..5).map(|i| inbound(&format!("m{}", i), &format!("c{}", i), at_minute(0))));
+        let mut visited = walk(&mut conversations, 2, at_minute(60), |_| {});
+        visited.sort();
+        assert_eq!(visited, ["m0", "m1", "m2", "m3", "m4", "m9"]);
+    }
+
+    #[test]
+    fn new_conversations_do_not_shift_the_walk() {
+        let mut conversations: Vec<Conversation> = (0..6)
+            .map(|i| inbound(&format!("m{}", i), &format!("c{}", i), at_minute(i)))
+            .collect();
+        let mut arrived = 0;
+        let visited = walk(&mut conversations, 2, at_minute(10), |conversations| {
+            arrived += 1;
+            conversations.push(inbound(
+                &format!("new{}", arrived),
+                "c0",
+                at_minute(10 + arrived),
+            ));
+        });
+        assert_eq!(visited, ["m5", "m4", "m3", "m2", "m1", "m0"]);
+    }
+
+    #[test]
+    fn returns_each_contact_once() {
+        let mut conversations = vec![
+            inbound("m3", "c1", at_minute(3)),
+            inbound("m2", "c2", at_minute(2)),
+            inbound("m1", "c1", at_minute(1)),
+        ];
+        let visited = walk(&mut conversations, 2, at_minute(60), |_| {});
+        assert_eq!(visited, ["m3", "m2"]);
+    }
+}
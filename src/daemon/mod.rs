@@ -1,18 +1,22 @@
-use crate::cmd::reminders::send_reminder_to_contact;
 use crate::config;
-use crate::core::wacraft::client::WacraftClient;
+use crate::config::models::CronSchedule;
+use crate::core::engine::{ReminderEngine, RuleSelection};
+use crate::store::activity_index::{self, ActivityIndex, IndexedContact};
+use crate::store::ledger;
 use anyhow::{Context, Result};
+use chrono::Utc;
+use chrono_tz::Tz;
 use log::info;
 use log::{error, warn};
+use schedule::{Scheduler, Trigger};
 use std::fs::{self, OpenOptions};
-use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use tokio::time::{Duration, interval};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet};
 pub mod pid;
+pub mod schedule;
 use log::LevelFilter;
-use simple_logging;
-
-const LOG_FILE: &str = ".wacraft-reminders.log";
 
 /// Configures the logger to write to a file in detached mode,
 /// or to the console in foreground mode.
@@ -21,12 +25,12 @@ fn setup_logging(detached: bool) -> Result<()> {
 
     if detached {
         // In detached mode, log to a file
+        let log_path = config::get_log_path()?;
         let log_file = OpenOptions::new()
             .create(true)
-            .write(true)
             .append(true)
-            .open(LOG_FILE)
-            .context("Failed to open or create log file")?;
+            .open(&log_path)
+            .with_context(|| format!("Failed to open or create log file: {:?}", log_path))?;
 
         simple_logging::log_to(log_file, log_level);
     } else {
@@ -37,94 +41,212 @@ fn setup_logging(detached: bool) -> Result<()> {
     Ok(())
 }
 
+/// The options a daemon process runs with.
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Seconds between runs, when no cron `schedule` is given.
+    pub interval_secs: u64,
+    /// A cron expression for when to run, replacing the interval.
+    pub schedule: Option<CronSchedule>,
+    /// The timezone cron expressions are evaluated in.
+    pub timezone: Tz,
+    pub batch_size: u32,
+    /// How many contacts are processed at the same time.
+    pub concurrency: u32,
+    pub mock: bool,
+}
+
 /// The main entry point for the daemon's run logic.
-pub async fn run_daemon_process(
-    interval_secs: u64,
-    batch_size: u32,
-    mock: bool,
-    detached: bool,
-) -> Result<()> {
+pub async fn run_daemon_process(options: DaemonOptions, detached: bool) -> Result<()> {
     setup_logging(detached)?;
     // Write the PID file now that the process is running
     if detached {
         pid::write_pid_file()?;
     }
 
-    info!(
-        "Daemon process started. Interval: {}s, Batch Size: {}.",
-        interval_secs, batch_size
-    );
+    let trigger = match &options.schedule {
+        Some(schedule) => {
+            info!(
+                "Daemon process started. Schedule: '{}' ({}), Batch Size: {}, Concurrency: {}.",
+                schedule.0.source(),
+                options.timezone,
+                options.batch_size,
+                options.concurrency
+            );
+            Trigger::Cron(schedule.clone())
+        }
+        None => {
+            info!(
+                "Daemon process started. Interval: {}s, Batch Size: {}, Concurrency: {}.",
+                options.interval_secs, options.batch_size, options.concurrency
+            );
+            Trigger::Interval(chrono::Duration::seconds(options.interval_secs as i64))
+        }
+    };
 
-    let mut timer = interval(Duration::from_secs(interval_secs));
+    let mut scheduler = Scheduler::new(trigger, options.timezone, Utc::now());
 
     loop {
-        timer.tick().await;
-        info!("Daemon tick: Starting reminder processing cycle.");
-        if let Err(e) = process_reminders_cycle(batch_size, mock).await {
-            error!("Error during reminder processing cycle: {:?}", e);
+        // Pick up rule schedules added or changed since the last run.
+        match config::load_reminders() {
+            Ok(rules) => scheduler.sync_rules(&rules, Utc::now()),
+            Err(e) => error!("Failed to load reminder schedules: {:?}", e),
         }
-    }
-}
 
-/// Executes a single cycle of fetching all conversations and processing reminders.
-async fn process_reminders_cycle(batch_size: u32, mock: bool) -> Result<()> {
-    let settings = config::load_settings().context("Daemon: Failed to load settings.json")?;
-    let client = WacraftClient::new(settings.wacraft.clone());
-    let mut offset = 0;
+        let Some(wake_at) = scheduler.next_wake() else {
+            anyhow::bail!("Nothing is scheduled to run. Check the daemon and rule schedules.");
+        };
+        if let Ok(wait) = wake_at.signed_duration_since(Utc::now()).to_std() {
+            info!("Next reminder processing cycle at {}.", wake_at);
+            tokio::time::sleep(wait).await;
+        }
 
-    loop {
+        let Some(selection) = scheduler.take_due(Utc::now()) else {
+            continue;
+        };
         info!(
-            "Fetching conversations batch: limit={}, offset={}",
-            batch_size, offset
+            "Daemon tick: Starting reminder processing cycle for {}.",
+            selection
         );
+        if let Err(e) = process_reminders_cycle(&options, selection).await {
+            error!("Error during reminder processing cycle: {:?}", e);
+        }
+    }
+}
 
-        let conversations = client
-            .get_conversations(batch_size, offset, None)
-            .await
-            .context("Daemon: Failed to fetch conversations batch")?;
+/// Executes a single cycle of syncing the activity index and processing reminders.
+///
+/// The index is first brought up to date with the conversations that changed since
+/// the last cycle; the rules are then evaluated for every indexed contact. Contacts
+/// are processed by up to `concurrency` tasks at once. All tasks share one engine and
+/// one copy of the delivery ledger, so rules and the ledger are loaded once per
+/// cycle, and the API client's access token and
+/// requests-per-second limit, the pooled SMTP connections and the HTTP client are
+/// shared by every contact.
+async fn process_reminders_cycle(options: &DaemonOptions, selection: RuleSelection) -> Result<()> {
+    let engine =
+        Arc::new(ReminderEngine::load(options.mock).context("Daemon: Failed to set up the cycle")?);
+    // The index can hold every contact of the account, and reading or writing it
+    // blocks, so both happen off the async runtime.
+    let mut index = tokio::task::spawn_blocking(activity_index::load)
+        .await
+        .context("Daemon: Activity index load task failed")?
+        .context("Daemon: Failed to load the activity index")?;
+    let changed = index
+        .sync(engine.client(), options.batch_size)
+        .await
+        .context("Daemon: Failed to sync the activity index")?;
+    info!(
+        "Synced the activity index: {} contact(s) changed, {} indexed.",
+        changed,
+        index.contacts.len()
+    );
 
-        if conversations.is_empty() {
-            info!("No more conversations to process in this cycle.");
-            break;
-        }
+    // Each contact only reads and records its own steps, so a snapshot taken now
+    // stays accurate for the whole cycle.
+    let delivery_ledger =
+        Arc::new(ledger::load().context("Daemon: Failed to load the delivery ledger")?);
+    let selection = Arc::new(selection);
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1) as usize));
+    let mut tasks = JoinSet::new();
+    let contacts: Vec<(String, IndexedContact)> = index
+        .contacts
+        .iter()
+        .map(|(contact_id, entry)| (contact_id.clone(), entry.clone()))
+        .collect();
 
-        for conversation in &conversations {
-            if let Some(contact) = &conversation.to_contact {
-                let contact_id = &contact.id;
-                match send_reminder_to_contact(contact_id, &settings, Some(conversation), mock)
-                    .await
-                {
-                    Ok(_) => info!("Successfully processed contact ID: {}", contact_id),
-                    Err(e) => warn!(
-                        "Failed to process reminder for contact ID {}: {:?}",
-                        contact_id, e
-                    ),
-                }
+    for (contact_id, mut entry) in contacts {
+        // Wait for a free slot, so at most `concurrency` contacts are in flight.
+        let permit = permits.clone().acquire_owned().await?;
+        let (engine, delivery_ledger, selection) =
+            (engine.clone(), delivery_ledger.clone(), selection.clone());
+        tasks.spawn(async move {
+            let _permit = permit;
+            match engine
+                .process_indexed_contact(&contact_id, &mut entry, &delivery_ledger, &selection)
+                .await
+            {
+                Ok(_) => info!("Successfully processed contact ID: {}", contact_id),
+                Err(e) => warn!(
+                    "Failed to process reminder for contact ID {}: {:?}",
+                    contact_id, e
+                ),
             }
+            // Hand the entry back, since evaluating it may have scanned the history.
+            (contact_id, entry)
+        });
+        // Reap finished tasks as we go, so their results don't pile up.
+        while let Some(result) = tasks.try_join_next() {
+            keep_task_result(&mut index, result);
         }
-        offset += batch_size;
     }
+    while let Some(result) = tasks.join_next().await {
+        keep_task_result(&mut index, result);
+    }
+
+    tokio::task::spawn_blocking(move || activity_index::save(&index))
+        .await
+        .context("Daemon: Activity index save task failed")?
+        .context("Daemon: Failed to save the activity index")?;
     info!("Finished reminder processing cycle.");
     Ok(())
 }
 
+/// Stores the entry a contact task handed back in the index, or logs a task that
+/// panicked. Other failures are logged by the task itself.
+fn keep_task_result(
+    index: &mut ActivityIndex,
+    result: Result<(String, IndexedContact), JoinError>,
+) {
+    match result {
+        Ok((contact_id, entry)) => {
+            index.contacts.insert(contact_id, entry);
+        }
+        Err(e) => error!("A contact processing task failed: {:?}", e),
+    }
+}
+
 /// Detaches the current process to run in the background.
-pub fn detach_process(interval_secs: u64, batch_size: u32, mock: bool) -> Result<()> {
+pub fn detach_process(options: &DaemonOptions) -> Result<()> {
     info!("Detaching daemon process...");
     let self_exe = std::env::current_exe().context("Failed to get current executable path")?;
 
     // Build the arguments vector to pass to the new process.
-    let mut args = vec![
+    // Global flags go before the subcommand, so the child reads the same files.
+    let overrides = config::path_overrides();
+    let mut args = Vec::new();
+    if let Some(path) = &overrides.settings {
+        args.push("--settings-config".to_string());
+        args.push(path.display().to_string());
+    }
+    if let Some(path) = &overrides.reminders {
+        args.push("--reminders-config".to_string());
+        args.push(path.display().to_string());
+    }
+    args.extend([
         "daemon".to_string(),
         "run".to_string(),
         "--internal-run-detached".to_string(),
-        "--interval".to_string(),
-        interval_secs.to_string(),
         "--batch-size".to_string(),
-        batch_size.to_string(),
-    ];
+        options.batch_size.to_string(),
+        "--concurrency".to_string(),
+        options.concurrency.to_string(),
+        "--timezone".to_string(),
+        options.timezone.to_string(),
+    ]);
+
+    match &options.schedule {
+        Some(schedule) => {
+            args.push("--schedule".to_string());
+            args.push(schedule.0.source().to_string());
+        }
+        None => {
+            args.push("--interval".to_string());
+            args.push(options.interval_secs.to_string());
+        }
+    }
 
-    if mock {
+    if options.mock {
         args.push("--mock".to_string());
     }
 
@@ -175,12 +297,12 @@ pub fn stop_daemon() -> Result<()> {
 
 /// Displays the last few lines of the daemon's log file.
 pub fn show_logs() -> Result<()> {
-    let log_path = PathBuf::from(LOG_FILE);
+    let log_path = config::get_log_path()?;
     if !log_path.exists() {
         anyhow::bail!("Log file not found. Has the daemon run yet?");
     }
-    let content = fs::read_to_string(log_path)?;
-    println!("--- Last logs from {} ---", LOG_FILE);
+    let content = fs::read_to_string(&log_path)?;
+    println!("--- Last logs from {} ---", log_path.display());
     // Simple implementation: print the whole file. A real-world app might use `tail`.
     println!("{}", content);
     Ok(())
@@ -0,0 +1,104 @@
+use crate::config::models::{CronSchedule, ReminderRule};
+use crate::core::engine::RuleSelection;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use std::collections::{HashMap, HashSet};
+
+/// When the daemon runs the rules that don't have their own schedule.
+#[derive(Debug, Clone)]
+pub enum Trigger {
+    /// A fixed number of seconds between runs, starting immediately.
+    Interval(Duration),
+    /// The fire times of a cron expression.
+    Cron(CronSchedule),
+}
+
+/// Tracks when the daemon's trigger and each rule's own schedule fire next.
+#[derive(Debug)]
+pub struct Scheduler {
+    trigger: Trigger,
+    timezone: Tz,
+    next_default: Option<DateTime<Utc>>,
+    /// Next fire time per scheduled rule, with the schedule it was computed from.
+    next_rules: HashMap<String, (CronSchedule, DateTime<Utc>)>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler whose cron expressions are evaluated in `timezone`.
+    pub fn new(trigger: Trigger, timezone: Tz, now: DateTime<Utc>) -> Self {
+        let next_default = match &trigger {
+            Trigger::Interval(_) => Some(now),
+            Trigger::Cron(schedule) => schedule.next_after(now, timezone),
+        };
+        Self {
+            trigger,
+            timezone,
+            next_default,
+            next_rules: HashMap::new(),
+        }
+    }
+
+    /// Brings the per-rule schedules in line with the current rules. Rules whose
+    /// schedule is unchanged keep their pending fire time.
+    pub fn sync_rules(&mut self, rules: &[ReminderRule], now: DateTime<Utc>) {
+        let mut next_rules = HashMap::new();
+        for rule in rules {
+            let Some(schedule) = &rule.schedule else {
+                continue;
+            };
+            let pending = self
+                .next_rules
+                .get(&rule.name)
+                .filter(|(previous, _)| previous.0.source() == schedule.0.source())
+                .map(|(_, at)| *at);
+            if let Some(at) = pending.or_else(|| schedule.next_after(now, self.timezone)) {
+                next_rules.insert(rule.name.clone(), (schedule.clone(), at));
+            }
+        }
+        self.next_rules = next_rules;
+    }
+
+    /// Returns the earliest upcoming fire time, if anything is scheduled at all.
+    pub fn next_wake(&self) -> Option<DateTime<Utc>> {
+        self.next_rules
+            .values()
+            .map(|(_, at)| *at)
+            .chain(self.next_default)
+            .min()
+    }
+
+    /// Returns which rules are due at `now`, if any, and advances their fire times.
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Option<RuleSelection> {
+        let default_due = self.next_default.is_some_and(|at| at <= now);
+        if default_due {
+            self.next_default = match &self.trigger {
+                Trigger::Interval(every) => {
+                    let next = self.next_default.map(|at| at + *every);
+                    // Don't try to catch up on runs missed during a long cycle.
+                    next.filter(|at| *at > now).or(Some(now + *every))
+                }
+                Trigger::Cron(schedule) => schedule.next_after(now, self.timezone),
+            };
+        }
+
+        let mut due_rules = HashSet::new();
+        for (name, (schedule, at)) in self.next_rules.iter_mut() {
+            if *at > now {
+                continue;
+            }
+            due_rules.insert(name.clone());
+            // A schedule with no further fire times never runs again.
+            *at = schedule
+                .next_after(now, self.timezone)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        }
+
+        if !default_due && due_rules.is_empty() {
+            return None;
+        }
+        Some(RuleSelection::Scheduled {
+            default: default_due,
+            rules: due_rules,
+        })
+    }
+}
@@ -0,0 +1,68 @@
+//! Builders for the values that the unit tests of several modules share.
+
+use crate::core::wacraft::models::{Conversation, MessagingProductContact, NIL_UUID};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+/// Returns a time in March 2026. The 9th, which the tests are set around, is a Monday.
+pub fn march(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 3, day, hour, minute, 0).unwrap()
+}
+
+/// Returns the time `hours` after midnight UTC on Monday, March 9.
+pub fn at_hour(hours: i64) -> DateTime<Utc> {
+    march(9, 0, 0) + Duration::hours(hours)
+}
+
+/// Returns the time `minutes` after midnight UTC on Monday, March 9.
+pub fn at_minute(minutes: i64) -> DateTime<Utc> {
+    march(9, 0, 0) + Duration::minutes(minutes)
+}
+
+/// Returns the WhatsApp contact `mp1`, Ana Souza, created at `created_at`.
+pub fn contact(email: Option<&str>, created_at: DateTime<Utc>) -> MessagingProductContact {
+    serde_json::from_value(serde_json::json!({
+        "contact_id": "ct1",
+        "messaging_product_id": "whatsapp",
+        "blocked": null,
+        "last_read_at": null,
+        "contact": {
+            "id": "ct1",
+            "name": "Ana Souza",
+            "email": email,
+            "photo_path": null,
+            "created_at": created_at,
+            "updated_at": created_at,
+        },
+        "product_details": {"wa_id": "5511999990000", "phone_number": "+55 11 99999-0000"},
+        "id": "mp1",
+        "created_at": created_at,
+        "updated_at": created_at,
+    }))
+    .unwrap()
+}
+
+/// Returns a message the contact sent us.
+pub fn inbound(id: &str, contact_id: &str, created_at: DateTime<Utc>) -> Conversation {
+    message(id, contact_id, NIL_UUID, created_at)
+}
+
+/// Returns a message we sent the contact.
+pub fn outbound(id: &str, contact_id: &str, created_at: DateTime<Utc>) -> Conversation {
+    message(id, NIL_UUID, contact_id, created_at)
+}
+
+fn message(id: &str, from_id: &str, to_id: &str, created_at: DateTime<Utc>) -> Conversation {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "from_id": from_id,
+        "to_id": to_id,
+        "from": null,
+        "to": null,
+        "created_at": created_at,
+        "updated_at": created_at,
+        "messaging_product_id": "whatsapp",
+        "receiver_data": null,
+        "deleted_at": null,
+    }))
+    .unwrap()
+}
@@ -4,6 +4,9 @@ mod cmd;
 mod config;
 mod core;
 mod daemon;
+#[cfg(test)]
+mod fixtures;
+mod store;
 
 #[derive(Parser)]
 #[command(
@@ -48,6 +51,7 @@ enum Commands {
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    config::set_path_overrides(cli.settings_config, cli.reminders_config)?;
 
     match cli.command {
         Commands::Config { action } => {
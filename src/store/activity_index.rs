@@ -0,0 +1,203 @@
+use crate::config;
+use crate::core::wacraft::client::WacraftClient;
+use crate::core::wacraft::models::{ContactActivity, Conversation};
+use crate::core::wacraft::pager::ConversationPager;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What is known locally about a contact's activity.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IndexedContact {
+    pub messaging_product_id: String,
+    #[serde(flatten)]
+    pub activity: ContactActivity,
+    /// Whether `activity` came from scanning the contact's message history, and no
+    /// message arrived since. Otherwise only the latest message's direction is known.
+    #[serde(default)]
+    pub history_scanned: bool,
+    /// The contact's latest message, with the contact embedded when the API included it.
+    pub last_message: Conversation,
+}
+
+impl IndexedContact {
+    fn new(conversation: Conversation, contact_id: &str) -> Self {
+        let mut activity = ContactActivity::default();
+        activity.observe(&conversation, contact_id);
+        Self {
+            messaging_product_id: conversation.messaging_product_id.clone(),
+            activity,
+            history_scanned: false,
+            last_message: conversation,
+        }
+    }
+}
+
+/// A local index of contact ID → last inbound and outbound time, kept up to date
+/// incrementally so the daemon doesn't download every conversation on each run.
+/// Stored as `activity_index.json` next to the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ActivityIndex {
+    /// The snapshot time of the last sync. Conversations created after it are not
+    /// in the index yet.
+    #[serde(default)]
+    pub synced_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub contacts: HashMap<String, IndexedContact>,
+}
+
+impl ActivityIndex {
+    /// Brings the index up to date with the conversations that changed since the
+    /// last sync. They are walked newest first, stopping at the first one from
+    /// before the last sync that is already indexed, so messages stamped slightly
+    /// before it (clock skew, late delivery) are still picked up. The first sync
+    /// backfills every conversation. Returns how many contacts changed.
+    pub async fn sync(&mut self, client: &WacraftClient, batch_size: u32) -> Result<usize> {
+        let snapshot = Utc::now();
+        let mut pager = ConversationPager::new(client, batch_size, snapshot);
+        let mut changed = 0;
+
+        while let Some(page) = pager.next_page().await? {
+            if self.sync_page(page, &mut changed) {
+                break;
+            }
+        }
+        self.synced_at = Some(snapshot);
+        Ok(changed)
+    }
+
+    /// Records a page of the sync walk, counting the contacts that changed.
+    /// Returns whether the walk caught up with the last sync and can stop.
+    fn sync_page(&mut self, page: Vec<Conversation>, changed: &mut usize) -> bool {
+        for conversation in page {
+            let before_last_sync = self
+                .synced_at
+                .is_some_and(|synced_at| conversation.created_at < synced_at);
+            if self.observe(conversation) {
+                *changed += 1;
+            } else if before_last_sync {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Records a contact's latest message. Returns whether it was new.
+    ///
+    /// Only the message's own direction is known to be current: the contact may
+    /// have exchanged other messages since the last sync, so the other direction is
+    /// forgotten until the history is scanned again.
+    fn observe(&mut self, conversation: Conversation) -> bool {
+        let Some(contact_id) = conversation.contact_id().map(str::to_string) else {
+            return false;
+        };
+        if let Some(entry) = self.contacts.get(&contact_id)
+            && entry.last_message.id == conversation.id
+        {
+            return false;
+        }
+        self.contacts.insert(
+            contact_id.clone(),
+            IndexedContact::new(conversation, &contact_id),
+        );
+        true
+    }
+}
+
+/// Loads the activity index from disk. A missing file yields an empty index, which
+/// the next sync backfills.
+pub fn load() -> Result<ActivityIndex> {
+    let path = config::get_activity_index_path()?;
+    if !path.exists() {
+        return Ok(ActivityIndex::default());
+    }
+    config::read_json_file(&path)
+}
+
+/// Writes the activity index to disk. It holds contact details and messages, so
+/// only the user may read it.
+pub fn save(index: &ActivityIndex) -> Result<()> {
+    let path = config::get_activity_index_path()?;
+    let _lock = config::lock_file(&path)?;
+    config::write_private_json_file(&path, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{at_minute, inbound, outbound};
+
+    /// An index last synced at minute 10, knowing c1's and c2's latest messages.
+    fn synced_index() -> ActivityIndex {
+        let mut index = ActivityIndex {
+            synced_at: Some(at_minute(10)),
+            ..ActivityIndex::default()
+        };
+        index.observe(inbound("m1", "c1", at_minute(5)));
+        index.observe(inbound("m2", "c2", at_minute(1)));
+        index
+    }
+
+    #[test]
+    fn sync_stops_at_the_first_known_message_from_before_the_last_sync() {
+        let mut index = synced_index();
+        let mut changed = 0;
+        let page = vec![
+            inbound("m3", "c3", at_minute(12)),
+            // Stamped before the last sync, but new: picked up all the same.
+            inbound("m4", "c4", at_minute(9)),
+            inbound("m1", "c1", at_minute(5)),
+            inbound("m0", "c0", at_minute(4)),
+        ];
+        assert!(index.sync_page(page, &mut changed));
+        assert_eq!(changed, 2);
+        assert!(index.contacts.contains_key("c4"));
+        assert!(!index.contacts.contains_key("c0"));
+    }
+
+    #[test]
+    fn sync_continues_past_known_messages_from_after_the_last_sync() {
+        let mut index = synced_index();
+        index.observe(inbound("m5", "c5", at_minute(11)));
+        let mut changed = 0;
+        let page = vec![
+            inbound("m5", "c5", at_minute(11)),
+            inbound("m6", "c6", at_minute(10)),
+        ];
+        assert!(!index.sync_page(page, &mut changed));
+        assert_eq!(changed, 1);
+    }
+
+    #[test]
+    fn first_sync_reads_everything() {
+        let mut index = ActivityIndex::default();
+        let mut changed = 0;
+        let page = vec![
+            outbound("m2", "c2", at_minute(2)),
+            inbound("m1", "c1", at_minute(1)),
+        ];
+        assert!(!index.sync_page(page, &mut changed));
+        assert_eq!(changed, 2);
+        assert_eq!(
+            index.contacts["c2"].activity.last_outbound_at,
+            Some(at_minute(2))
+        );
+        assert_eq!(
+            index.contacts["c1"].activity.last_inbound_at,
+            Some(at_minute(1))
+        );
+    }
+
+    #[test]
+    fn observe_forgets_the_other_direction() {
+        let mut index = synced_index();
+        assert!(!index.observe(inbound("m1", "c1", at_minute(5))));
+        assert!(index.observe(outbound("m7", "c1", at_minute(20))));
+
+        let entry = &index.contacts["c1"];
+        assert_eq!(entry.activity.last_outbound_at, Some(at_minute(20)));
+        assert_eq!(entry.activity.last_inbound_at, None);
+        assert!(!entry.history_scanned);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::config;
+use crate::core::action::RenderedAction;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A reminder action that could not be delivered, even after retries.
+/// It keeps the rendered payload so that it can be replayed as it was.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetter {
+    pub id: String,
+    pub contact_id: String,
+    pub rule_name: String,
+    /// The inactivity episode the rule was evaluated for.
+    pub activity_at: DateTime<Utc>,
+    /// The position of the action among the rule's actions.
+    pub action_index: usize,
+    pub payload: RenderedAction,
+    /// Whether the rule runs its actions as `first_success` alternatives, so that
+    /// delivering any one of them settles the others.
+    #[serde(default)]
+    pub first_success: bool,
+    /// The error of the latest failed attempt.
+    pub error: String,
+    /// How many times delivery was given up on, counting replays.
+    pub failures: u32,
+    pub first_failed_at: DateTime<Utc>,
+    pub last_failed_at: DateTime<Utc>,
+}
+
+impl DeadLetter {
+    fn is_same_action(&self, other: &DeadLetter) -> bool {
+        self.contact_id == other.contact_id
+            && self.rule_name == other.rule_name
+            && self.activity_at == other.activity_at
+            && self.action_index == other.action_index
+    }
+}
+
+/// The persisted failed deliveries, stored as `dead_letters.json` next to the ledger.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeadLetters {
+    #[serde(default)]
+    pub entries: Vec<DeadLetter>,
+}
+
+impl DeadLetters {
+    /// Adds a failed delivery. A failure of an action that is already stored replaces
+    /// the stored payload and error, keeping its ID.
+    fn insert(&mut self, mut letter: DeadLetter) {
+        match self.entries.iter_mut().find(|l| l.is_same_action(&letter)) {
+            Some(existing) => {
+                existing.payload = letter.payload;
+                existing.error = letter.error;
+                existing.failures += 1;
+                existing.last_failed_at = letter.last_failed_at;
+            }
+            None => {
+                while self.entries.iter().any(|l| l.id == letter.id) {
+                    letter.id = new_id();
+                }
+                self.entries.push(letter);
+            }
+        }
+    }
+}
+
+/// Loads the dead letters from disk. A missing file yields no entries.
+pub fn load() -> Result<DeadLetters> {
+    let path = config::get_dead_letters_path()?;
+    if !path.exists() {
+        return Ok(DeadLetters::default());
+    }
+    config::read_json_file(&path)
+}
+
+/// Stores a failed delivery of an action.
+pub async fn add(
+    contact_id: &str,
+    rule_name: &str,
+    activity_at: DateTime<Utc>,
+    action_index: usize,
+    payload: RenderedAction,
+    first_success: bool,
+    error: String,
+) -> Result<()> {
+    let now = Utc::now();
+    let letter = DeadLetter {
+        id: new_id(),
+        contact_id: contact_id.to_string(),
+        rule_name: rule_name.to_string(),
+        activity_at,
+        action_index,
+        payload,
+        first_success,
+        error,
+        failures: 1,
+        first_failed_at: now,
+        last_failed_at: now,
+    };
+    update(|letters| {
+        letters.insert(letter);
+        true
+    })
+    .await
+}
+
+/// Records that a stored delivery failed again when it was replayed.
+pub async fn record_failure(id: &str, error: String) -> Result<()> {
+    let id = id.to_string();
+    update(
+        move |letters| match letters.entries.iter_mut().find(|l| l.id == id) {
+            Some(letter) => {
+                letter.error = error;
+                letter.failures += 1;
+                letter.last_failed_at = Utc::now();
+                true
+            }
+            None => false,
+        },
+    )
+    .await
+}
+
+/// Removes a delivery, e.g. after it was replayed successfully.
+pub async fn remove(id: &str) -> Result<()> {
+    let id = id.to_string();
+    remove_where(move |l| l.id == id).await
+}
+
+/// Removes the stored failures of the given actions of a rule for a contact's episode,
+/// once they no longer need a replay.
+pub async fn resolve(
+    contact_id: &str,
+    rule_name: &str,
+    activity_at: DateTime<Utc>,
+    action_indexes: &[usize],
+) -> Result<()> {
+    if action_indexes.is_empty() || !config::get_dead_letters_path()?.exists() {
+        return Ok(());
+    }
+    let (contact_id, rule_name) = (contact_id.to_string(), rule_name.to_string());
+    let action_indexes = action_indexes.to_vec();
+    remove_where(move |l| {
+        l.contact_id == contact_id
+            && l.rule_name == rule_name
+            && l.activity_at == activity_at
+            && action_indexes.contains(&l.action_index)
+    })
+    .await
+}
+
+/// Removes the stored failures of every action of a rule for a contact's episode,
+/// e.g. once one of the alternatives of a `first_success` rule was replayed.
+pub async fn resolve_rule(
+    contact_id: &str,
+    rule_name: &str,
+    activity_at: DateTime<Utc>,
+) -> Result<()> {
+    let (contact_id, rule_name) = (contact_id.to_string(), rule_name.to_string());
+    remove_where(move |l| {
+        l.contact_id == contact_id && l.rule_name == rule_name && l.activity_at == activity_at
+    })
+    .await
+}
+
+async fn remove_where(matches: impl Fn(&DeadLetter) -> bool + Send + 'static) -> Result<()> {
+    update(move |letters| {
+        let before = letters.entries.len();
+        letters.entries.retain(|l| !matches(l));
+        letters.entries.len() != before
+    })
+    .await
+}
+
+/// Applies a change to the dead letters on disk under a file lock, like the ledger.
+/// `change` returns whether anything changed, so untouched files aren't rewritten.
+/// The file holds rendered messages with contact details, so only the user may read it.
+async fn update(change: impl FnOnce(&mut DeadLetters) -> bool + Send + 'static) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let path = config::get_dead_letters_path()?;
+        let _lock = config::lock_file(&path)?;
+
+        let mut letters = load()?;
+        if change(&mut letters) {
+            config::write_private_json_file(&path, &letters)?;
+        }
+        Ok(())
+    })
+    .await
+    .context("Failed deliveries update task failed")?
+}
+
+/// Returns a short random ID that is easy to type on the command line.
+fn new_id() -> String {
+    format!("{:08x}", fastrand::u32(..))
+}
@@ -0,0 +1,355 @@
+use crate::config;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What happened to a sequence step during an inactivity episode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// The step's action ran successfully.
+    #[default]
+    Delivered,
+    /// The step was passed over because the steps it requires were not delivered.
+    Skipped,
+    /// The step is due but outside its sending window. It runs once the window opens.
+    Deferred,
+}
+
+impl DeliveryStatus {
+    /// Returns whether the step is finished for the episode and must not run again.
+    pub fn is_final(self) -> bool {
+        self != DeliveryStatus::Deferred
+    }
+}
+
+/// A single reminder step that was handled for a contact.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryRecord {
+    pub rule_name: String,
+    #[serde(default)]
+    pub status: DeliveryStatus,
+    /// The contact's last-activity timestamp the rule was evaluated against.
+    /// A new timestamp means a new inactivity episode.
+    pub activity_at: DateTime<Utc>,
+    #[serde(alias = "delivered_at")]
+    pub recorded_at: DateTime<Utc>,
+    /// For deferred steps, when the sending window opens next.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deferred_until: Option<DateTime<Utc>>,
+}
+
+/// The persisted record of which rules already fired for which contacts.
+/// Stored as `ledger.json` next to the other configuration files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Ledger {
+    #[serde(default)]
+    pub contacts: HashMap<String, Vec<DeliveryRecord>>,
+}
+
+impl Ledger {
+    /// Returns the record for `rule_name` in the contact's inactivity episode that
+    /// started at `activity_at`, if the rule was already handled.
+    pub fn find(
+        &self,
+        contact_id: &str,
+        rule_name: &str,
+        activity_at: DateTime<Utc>,
+    ) -> Option<&DeliveryRecord> {
+        self.episode(contact_id, activity_at)
+            .find(|r| r.rule_name == rule_name)
+    }
+
+    /// Returns the names of the rules delivered in the given episode, oldest first.
+    pub fn delivered_rules(&self, contact_id: &str, activity_at: DateTime<Utc>) -> Vec<&str> {
+        self.episode(contact_id, activity_at)
+            .filter(|r| r.status == DeliveryStatus::Delivered)
+            .map(|r| r.rule_name.as_str())
+            .collect()
+    }
+
+    /// Iterates over every deferred step as `(contact_id, record)` pairs.
+    pub fn deferred(&self) -> impl Iterator<Item = (&str, &DeliveryRecord)> {
+        self.contacts.iter().flat_map(|(contact_id, records)| {
+            records
+                .iter()
+                .filter(|r| r.status == DeliveryStatus::Deferred)
+                .map(move |r| (contact_id.as_str(), r))
+        })
+    }
+
+    /// Returns whether the contact has deferred steps from an inactivity episode
+    /// older than the one that started at `activity_at`. Those will never be sent.
+    pub fn has_stale_deferrals(&self, contact_id: &str, activity_at: DateTime<Utc>) -> bool {
+        self.contacts
+            .get(contact_id)
+            .into_iter()
+            .flatten()
+            .any(|r| r.status == DeliveryStatus::Deferred && r.activity_at < activity_at)
+    }
+
+    /// Returns whether the contact was active again after the inactivity episode that
+    /// started at `activity_at`, i.e. the ledger already holds a newer episode.
+    pub fn is_past_episode(&self, contact_id: &str, activity_at: DateTime<Utc>) -> bool {
+        self.contacts
+            .get(contact_id)
+            .into_iter()
+            .flatten()
+            .any(|r| r.activity_at > activity_at)
+    }
+
+    /// Iterates over the contact's records for the given inactivity episode.
+    fn episode(
+        &self,
+        contact_id: &str,
+        activity_at: DateTime<Utc>,
+    ) -> impl Iterator<Item = &DeliveryRecord> {
+        self.contacts
+            .get(contact_id)
+            .into_iter()
+            .flatten()
+            .filter(move |r| r.activity_at == activity_at)
+    }
+
+    /// Adds a delivery record, dropping the contact's records from previous episodes.
+    /// A record for an episode older than the contact's current one is ignored, so it
+    /// can't wipe out the current episode. Returns whether the record was added.
+    fn insert(&mut self, contact_id: &str, record: DeliveryRecord) -> bool {
+        if self.is_past_episode(contact_id, record.activity_at) {
+            return false;
+        }
+        let records = self.contacts.entry(contact_id.to_string()).or_default();
+        records.retain(|r| r.activity_at == record.activity_at && r.rule_name != record.rule_name);
+        records.push(record);
+        true
+    }
+}
+
+/// Loads the ledger from disk. A missing file yields an empty ledger.
+pub fn load() -> Result<Ledger> {
+    let path = config::get_ledger_path()?;
+    if !path.exists() {
+        return Ok(Ledger::default());
+    }
+    config::read_json_file(&path)
+}
+
+/// Records what happened to `rule_name` for a contact in the given episode.
+pub async fn record(
+    contact_id: &str,
+    rule_name: &str,
+    activity_at: DateTime<Utc>,
+    status: DeliveryStatus,
+) -> Result<()> {
+    write_record(
+        contact_id,
+        DeliveryRecord {
+            rule_name: rule_name.to_string(),
+            status,
+            activity_at,
+            recorded_at: Utc::now(),
+            deferred_until: None,
+        },
+    )
+    .await
+}
+
+/// Records that `rule_name` is due for a contact but deferred until `until`.
+pub async fn record_deferral(
+    contact_id: &str,
+    rule_name: &str,
+    activity_at: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<()> {
+    write_record(
+        contact_id,
+        DeliveryRecord {
+            rule_name: rule_name.to_string(),
+            status: DeliveryStatus::Deferred,
+            activity_at,
+            recorded_at: Utc::now(),
+            deferred_until: Some(until),
+        },
+    )
+    .await
+}
+
+/// Drops the contact's deferred steps from inactivity episodes older than the one
+/// that started at `activity_at`, so they are no longer listed as pending.
+pub async fn prune_deferrals(contact_id: &str, activity_at: DateTime<Utc>) -> Result<()> {
+    let contact_id = contact_id.to_string();
+    update(move |ledger| {
+        let Some(records) = ledger.contacts.get_mut(&contact_id) else {
+            return false;
+        };
+        let before = records.len();
+        records.retain(|r| r.status != DeliveryStatus::Deferred || r.activity_at >= activity_at);
+        records.len() != before
+    })
+    .await
+}
+
+/// Adds a record to the ledger on disk.
+async fn write_record(contact_id: &str, record: DeliveryRecord) -> Result<()> {
+    let contact_id = contact_id.to_string();
+    update(move |ledger| ledger.insert(&contact_id, record)).await
+}
+
+/// Applies a change to the ledger on disk. The ledger is re-read under a file lock
+/// first, so that concurrent daemon and CLI runs don't drop each other's records.
+/// `change` returns whether anything changed, so untouched files aren't rewritten.
+///
+/// Locking and writing the file block, so they run off the async runtime.
+async fn update(change: impl FnOnce(&mut Ledger) -> bool + Send + 'static) -> Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let path = config::get_ledger_path()?;
+        let _lock = config::lock_file(&path)?;
+
+        let mut ledger = load()?;
+        if change(&mut ledger) {
+            config::write_json_file(&path, &ledger)?;
+        }
+        Ok(())
+    })
+    .await
+    .context("Ledger update task failed")?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::at_hour;
+
+    fn record(
+        rule_name: &str,
+        status: DeliveryStatus,
+        activity_at: DateTime<Utc>,
+    ) -> DeliveryRecord {
+        DeliveryRecord {
+            rule_name: rule_name.to_string(),
+            status,
+            activity_at,
+            recorded_at: activity_at,
+            deferred_until: None,
+        }
+    }
+
+    #[test]
+    fn find_only_matches_the_same_episode() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("nudge", DeliveryStatus::Delivered, at_hour(8)));
+
+        assert!(ledger.find("c1", "nudge", at_hour(8)).is_some());
+        assert!(ledger.find("c1", "nudge", at_hour(9)).is_none());
+        assert!(ledger.find("c1", "other", at_hour(8)).is_none());
+        assert!(ledger.find("c2", "nudge", at_hour(8)).is_none());
+    }
+
+    #[test]
+    fn delivered_rules_lists_the_episode_in_order() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("first", DeliveryStatus::Delivered, at_hour(8)));
+        ledger.insert(
+            "c1",
+            record("second", DeliveryStatus::Delivered, at_hour(8)),
+        );
+
+        assert_eq!(
+            ledger.delivered_rules("c1", at_hour(8)),
+            ["first", "second"]
+        );
+        assert!(ledger.delivered_rules("c1", at_hour(9)).is_empty());
+    }
+
+    #[test]
+    fn insert_starts_a_new_episode() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("first", DeliveryStatus::Delivered, at_hour(8)));
+        ledger.insert(
+            "c1",
+            record("second", DeliveryStatus::Delivered, at_hour(8)),
+        );
+        ledger.insert("c2", record("first", DeliveryStatus::Delivered, at_hour(8)));
+        // The contact was active again, so the old episode's records go.
+        ledger.insert(
+            "c1",
+            record("first", DeliveryStatus::Delivered, at_hour(10)),
+        );
+
+        assert_eq!(ledger.contacts["c1"].len(), 1);
+        assert!(ledger.find("c1", "second", at_hour(8)).is_none());
+        assert!(ledger.find("c1", "first", at_hour(10)).is_some());
+        assert!(ledger.find("c2", "first", at_hour(8)).is_some());
+    }
+
+    #[test]
+    fn delivered_rules_ignores_undelivered_steps() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("first", DeliveryStatus::Skipped, at_hour(8)));
+        ledger.insert("c1", record("second", DeliveryStatus::Deferred, at_hour(8)));
+        ledger.insert("c1", record("third", DeliveryStatus::Delivered, at_hour(8)));
+
+        assert_eq!(ledger.delivered_rules("c1", at_hour(8)), ["third"]);
+    }
+
+    #[test]
+    fn insert_replaces_a_deferral_once_delivered() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("nudge", DeliveryStatus::Deferred, at_hour(8)));
+        ledger.insert("c1", record("nudge", DeliveryStatus::Delivered, at_hour(8)));
+
+        assert_eq!(ledger.contacts["c1"].len(), 1);
+        assert_eq!(ledger.deferred().count(), 0);
+        assert_eq!(ledger.delivered_rules("c1", at_hour(8)), ["nudge"]);
+    }
+
+    #[test]
+    fn has_stale_deferrals_only_counts_older_episodes() {
+        let mut ledger = Ledger::default();
+        ledger.insert("c1", record("nudge", DeliveryStatus::Deferred, at_hour(8)));
+        ledger.insert("c2", record("nudge", DeliveryStatus::Delivered, at_hour(8)));
+
+        assert!(!ledger.has_stale_deferrals("c1", at_hour(8)));
+        assert!(ledger.has_stale_deferrals("c1", at_hour(10)));
+        assert!(!ledger.has_stale_deferrals("c2", at_hour(10)));
+        assert!(!ledger.has_stale_deferrals("c3", at_hour(10)));
+    }
+
+    #[test]
+    fn old_records_read_as_delivered() {
+        let ledger: Ledger = serde_json::from_str(
+            r#"{"contacts": {"c1": [{
+                "rule_name": "nudge",
+                "activity_at": "2026-03-09T08:00:00Z",
+                "delivered_at": "2026-03-09T12:00:00Z"
+            }]}}"#,
+        )
+        .unwrap();
+        assert_eq!(ledger.delivered_rules("c1", at_hour(8)), ["nudge"]);
+    }
+
+    #[test]
+    fn records_of_a_past_episode_leave_the_current_one_alone() {
+        let mut ledger = Ledger::default();
+        ledger.insert(
+            "c1",
+            record("first", DeliveryStatus::Delivered, at_hour(10)),
+        );
+        ledger.insert(
+            "c1",
+            record("second", DeliveryStatus::Delivered, at_hour(10)),
+        );
+
+        assert!(ledger.is_past_episode("c1", at_hour(8)));
+        assert!(!ledger.is_past_episode("c1", at_hour(10)));
+        // E.g. a replayed delivery that failed before the contact was active again.
+        assert!(!ledger.insert("c1", record("first", DeliveryStatus::Delivered, at_hour(8))));
+
+        assert_eq!(
+            ledger.delivered_rules("c1", at_hour(10)),
+            ["first", "second"]
+        );
+        assert!(ledger.find("c1", "first", at_hour(8)).is_none());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod activity_index;
+pub mod dead_letters;
+pub mod ledger;
+pub mod tokens;
@@ -0,0 +1,48 @@
+use crate::config;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+
+/// OAuth tokens for one Wacraft account, shared between all processes using the
+/// same configuration directory. Stored as `tokens.json` with 0600 permissions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedTokens {
+    /// The account the tokens belong to, so a changed `settings.json` never
+    /// reuses another account's tokens.
+    pub base_url: String,
+    pub email: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Expiration timestamp (Unix epoch) for the access token.
+    pub token_expires_at: i64,
+}
+
+/// Loads the cached tokens for the given account, if any.
+/// An unreadable cache is treated as empty; it is rewritten on the next login.
+pub fn load(base_url: &str, email: &str) -> Result<Option<CachedTokens>> {
+    let path = config::get_tokens_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let tokens: CachedTokens = match config::read_json_file(&path) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            log::warn!("Ignoring unreadable token cache: {:?}", e);
+            return Ok(None);
+        }
+    };
+    Ok(Some(tokens).filter(|t| t.base_url == base_url && t.email == email))
+}
+
+/// Atomically replaces the token cache. Callers should hold [`lock`].
+pub fn save(tokens: &CachedTokens) -> Result<()> {
+    let path = config::get_tokens_path()?;
+    config::write_private_json_file(&path, tokens)
+}
+
+/// Takes the cross-process lock that serializes token refreshes, so a rotated
+/// refresh token is never used twice by the CLI and the daemon at once.
+pub fn lock() -> Result<File> {
+    let path = config::get_tokens_path()?;
+    config::lock_file(&path)
+}